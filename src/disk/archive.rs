@@ -0,0 +1,120 @@
+use super::sync_dir;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Secondary storage which sealed chunks are moved to instead of being deleted
+/// when the log has more than `max_segments` chunks, e.g a bigger and slower
+/// disk. Archived chunks are still read through the log from the directory
+/// the store returns for them. Every log needs a store of its own. Logs of
+/// topics and partitions get theirs from `scope`
+pub trait ArchiveStore: fmt::Debug + Send + Sync {
+    /// Moves index and segment files of the chunk out of the log directory.
+    /// Files shouldn't be in `dir` anymore when this returns
+    fn archive(&self, dir: &Path, base_offset: u64) -> io::Result<()>;
+
+    /// Directory which has the files of the archived chunk
+    fn dir(&self, base_offset: u64) -> io::Result<PathBuf>;
+
+    /// Base offsets of all the archived chunks in any order
+    fn list(&self) -> io::Result<Vec<u64>>;
+
+    /// Deletes files of the archived chunk
+    fn remove(&self, base_offset: u64) -> io::Result<()>;
+
+    /// Separate store for the log named `name` inside a group of logs. Stores
+    /// which can't be split can't be shared by logs and error
+    fn scope(&self, name: &str) -> io::Result<Arc<dyn ArchiveStore>> {
+        let e = format!("Archive can't be split for log {}", name);
+        Err(io::Error::new(io::ErrorKind::Unsupported, e))
+    }
+}
+
+/// Archives chunks to a directory. Files are hard linked when the directory is
+/// on the same filesystem as the log and copied otherwise
+#[derive(Debug, Clone)]
+pub struct DirArchive {
+    dir: PathBuf,
+}
+
+impl DirArchive {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<DirArchive> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(DirArchive { dir })
+    }
+}
+
+impl ArchiveStore for DirArchive {
+    fn archive(&self, dir: &Path, base_offset: u64) -> io::Result<()> {
+        // files are removed from the log only after both of them are archived. a
+        // crash in between leaves the chunk in the log, which wins over the archive
+        for extension in ["segment", "index"] {
+            let file_name = format!("{:020}.{}", base_offset, extension);
+            let (source, target) = (dir.join(&file_name), self.dir.join(&file_name));
+            if fs::hard_link(&source, &target).is_ok() {
+                continue;
+            }
+
+            let tmp = self.dir.join(format!("{}.tmp", file_name));
+            fs::copy(&source, &tmp)?;
+            File::open(&tmp)?.sync_all()?;
+            fs::rename(tmp, target)?;
+        }
+
+        sync_dir(&self.dir)?;
+
+        for extension in ["index", "segment"] {
+            fs::remove_file(dir.join(format!("{:020}.{}", base_offset, extension)))?;
+        }
+
+        Ok(())
+    }
+
+    fn dir(&self, _base_offset: u64) -> io::Result<PathBuf> {
+        Ok(self.dir.clone())
+    }
+
+    fn list(&self) -> io::Result<Vec<u64>> {
+        // (index, segment) of every base offset
+        let mut files: BTreeMap<u64, (bool, bool)> = BTreeMap::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let stem = path.file_stem().and_then(|stem| stem.to_str());
+            let base_offset = match stem.map(|stem| (stem.parse::<u64>(), stem.len())) {
+                Some((Ok(base_offset), 20)) => base_offset,
+                _ => continue,
+            };
+
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("index") => files.entry(base_offset).or_default().0 = true,
+                Some("segment") => files.entry(base_offset).or_default().1 = true,
+                _ => (),
+            }
+        }
+
+        let base_offsets = files
+            .into_iter()
+            .filter(|(_, chunk)| *chunk == (true, true))
+            .map(|(base_offset, _)| base_offset)
+            .collect();
+
+        Ok(base_offsets)
+    }
+
+    fn remove(&self, base_offset: u64) -> io::Result<()> {
+        for extension in ["index", "segment"] {
+            fs::remove_file(self.dir.join(format!("{:020}.{}", base_offset, extension)))?;
+        }
+
+        Ok(())
+    }
+
+    fn scope(&self, name: &str) -> io::Result<Arc<dyn ArchiveStore>> {
+        Ok(Arc::new(DirArchive::new(self.dir.join(name))?))
+    }
+}
@@ -0,0 +1,408 @@
+use super::index::{Index, IndexEncoding, IndexInterval};
+use super::segment::{Segment, SegmentOptions, HEADER_WIDTH};
+
+use std::io;
+use std::path::Path;
+
+/// Segment of the log along with its index. Hides the difference between
+/// dense and sparse indexes from the log. Records of chunks with a sparse
+/// index are preceded by a header and unindexed records are found by walking
+/// these headers from the nearest indexed record
+pub(crate) struct Chunk {
+    pub index: Index,
+    pub segment: Segment,
+    interval: IndexInterval,
+    /// Number of records in the chunk
+    count: u64,
+}
+
+impl Chunk {
+    pub fn new(
+        dir: &Path,
+        base_offset: u64,
+        max_index_size: u64,
+        active: bool,
+        interval: IndexInterval,
+        encoding: IndexEncoding,
+        options: SegmentOptions,
+    ) -> io::Result<Chunk> {
+        let sparse = interval.is_sparse();
+        let index = Index::open(dir, base_offset, max_index_size, active, encoding, sparse)?;
+
+        // Legacy chunks are written before files had headers. They are still read
+        // and written in the old format while new chunks get headers
+        let legacy = index.is_legacy();
+        // only active segments are written to
+        let options = SegmentOptions {
+            preallocate: options.preallocate.filter(|_| active),
+            read_only: !active,
+            ..options
+        };
+
+        let segment = Segment::open(dir, base_offset, legacy, sparse, options)?;
+        if !legacy && segment.is_framed() != index.is_sparse() {
+            let e = format!("Segment {} doesn't match its index", base_offset);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        let mut chunk = Chunk {
+            index,
+            segment,
+            interval,
+            count: 0,
+        };
+
+        chunk.count = chunk.recount(false)?;
+        chunk.segment.set_next_offset(chunk.count);
+        Ok(chunk)
+    }
+
+    /// Opens chunk of a log which might still be written by someone else. Files
+    /// aren't modified. Records which aren't completely written yet are left out
+    pub fn open_read_only(
+        dir: &Path,
+        base_offset: u64,
+        options: SegmentOptions,
+    ) -> io::Result<Chunk> {
+        let mut index = Index::open_read_only(dir, base_offset)?;
+        let legacy = index.is_legacy();
+        let options = SegmentOptions {
+            preallocate: None,
+            read_only: true,
+            ..options
+        };
+
+        let segment = Segment::open(dir, base_offset, legacy, index.is_sparse(), options)?;
+        if !legacy && segment.is_framed() != index.is_sparse() {
+            let e = format!("Segment {} doesn't match its index", base_offset);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+
+        index.trim(segment.size())?;
+        let mut chunk = Chunk {
+            index,
+            segment,
+            interval: IndexInterval::default(),
+            count: 0,
+        };
+
+        chunk.count = chunk.recount(true)?;
+        Ok(chunk)
+    }
+
+    pub fn base_offset(&self) -> u64 {
+        self.index.base_offset()
+    }
+
+    /// Number of records
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Counts records of a sparse chunk by walking the headers after the last
+    /// indexed record. A segment which ends in the middle of a record wasn't
+    /// closed properly and is treated as corrupted like unclosed indexes unless
+    /// `partial` allows the partial record to be left out. Zeros which direct
+    /// I/O padded the last block with are trimmed
+    fn recount(&mut self, partial: bool) -> io::Result<u64> {
+        if !self.index.is_sparse() {
+            return Ok(self.index.count());
+        }
+
+        let base_offset = self.index.base_offset();
+        let entries = self.index.count();
+        if entries == 0 {
+            if self.segment.size() != 0 && !self.segment.is_padding(0)? {
+                let e = format!("Segment {} has records which aren't indexed", base_offset);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+
+            self.segment.trim_padding(0)?;
+            return Ok(0);
+        }
+
+        let (mut position, mut offset) = self.index.read(entries - 1)?;
+        let size = self.segment.size();
+        while position + HEADER_WIDTH <= size {
+            let (len, flags) = self.segment.read_header(position)?;
+            // zeros of direct I/O which look like headers of empty records
+            if len == 0 && flags == 0 && self.segment.is_padding(position)? {
+                warn!("Trimming padding of segment {} after a crash", base_offset);
+                self.segment.trim_padding(position)?;
+                return Ok(offset);
+            }
+
+            if partial && position + HEADER_WIDTH + len > size {
+                break;
+            }
+
+            position += HEADER_WIDTH + len;
+            offset += 1;
+        }
+
+        if position != size && !partial {
+            let e = format!(
+                "Segment {} has a partial record. Segment corrupted",
+                base_offset
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+
+        Ok(offset)
+    }
+
+    /// Appends record to the segment and indexes it if necessary. Returns
+    /// relative offset of the record
+    pub fn append(&mut self, record: &[u8], flags: u8) -> io::Result<u64> {
+        let offset = if self.index.is_sparse() {
+            let (offset, position) = self.segment.append_with_header(record, flags)?;
+            if self.should_index(offset, position)? {
+                self.index.write(position, offset, 0)?;
+            }
+
+            offset
+        } else {
+            let (offset, position) = self.segment.append(record)?;
+            self.index.write(position, record.len() as u64, flags)?;
+            offset
+        };
+
+        self.count += 1;
+        Ok(offset)
+    }
+
+    fn should_index(&self, offset: u64, position: u64) -> io::Result<bool> {
+        let entries = self.index.count();
+        if entries == 0 {
+            return Ok(true);
+        }
+
+        let index = match self.interval {
+            IndexInterval::Record => true,
+            IndexInterval::Records(n) => offset.is_multiple_of(n.max(1)),
+            IndexInterval::Bytes(n) => {
+                let (last_position, _) = self.index.read(entries - 1)?;
+                position - last_position >= n
+            }
+        };
+
+        Ok(index)
+    }
+
+    /// Returns position, length and flags of the record at the relative offset
+    pub fn entry(&mut self, offset: u64) -> io::Result<(u64, u64, u8)> {
+        if !self.index.is_sparse() {
+            return self.index.read_with_flags(offset);
+        }
+
+        if offset >= self.count {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Reading at an invalid offset",
+            ));
+        }
+
+        let position = self.header_position(offset)?;
+        let (len, flags) = self.segment.read_header(position)?;
+        Ok((position + HEADER_WIDTH, len, flags))
+    }
+
+    /// Returns starting position, size required to fit 'n' records, n (count).
+    /// Same as `Index::readv`. Positions and sizes of sparse chunks include
+    /// record headers
+    pub fn readv(
+        &mut self,
+        offset: u64,
+        size: u64,
+        max_count: u64,
+        strict: bool,
+    ) -> io::Result<(u64, u64, u64)> {
+        if !self.index.is_sparse() {
+            return self.index.readv(offset, size, max_count, strict);
+        }
+
+        if offset >= self.count {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Reading at an invalid offset",
+            ));
+        }
+
+        let start = self.header_position(offset)?;
+        let mut position = start;
+        let mut count = 0;
+        loop {
+            let (len, _) = self.segment.read_header(position)?;
+            if strict && position + HEADER_WIDTH + len - start > size {
+                break;
+            }
+
+            position += HEADER_WIDTH + len;
+            count += 1;
+
+            // size reached. include the last record even though it crosses boundary
+            let full = position - start >= size || count >= max_count;
+            if full || offset + count >= self.count {
+                break;
+            }
+        }
+
+        Ok((start, position - start, count))
+    }
+
+    /// Drops records at and after the relative offset
+    pub fn truncate(&mut self, offset: u64) -> io::Result<()> {
+        if offset >= self.count {
+            return Ok(());
+        }
+
+        let (position, entries) = if self.index.is_sparse() {
+            // entries are sorted by relative offset. truncation is rare enough to
+            // walk back from the last entry
+            let mut entries = self.index.count();
+            while entries > 0 && self.index.read(entries - 1)?.1 >= offset {
+                entries -= 1;
+            }
+
+            (self.header_position(offset)?, entries)
+        } else {
+            (self.index.read(offset)?.0, offset)
+        };
+
+        self.segment.truncate(position)?;
+        self.index.truncate(entries);
+        self.segment.set_next_offset(offset);
+        self.count = offset;
+        Ok(())
+    }
+
+    /// Position of the header of a record in a sparse chunk
+    fn header_position(&mut self, offset: u64) -> io::Result<u64> {
+        let (mut current, mut position) = self.index.lookup(offset)?;
+        while current < offset {
+            let (len, _) = self.segment.read_header(position)?;
+            position += HEADER_WIDTH + len;
+            current += 1;
+        }
+
+        Ok(position)
+    }
+
+    /// Syncs the index and records to the disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.index.sync()?;
+        self.segment.sync()
+    }
+
+    pub fn close(&mut self) -> io::Result<()> {
+        self.index.close()?;
+        self.segment.close()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Chunk;
+    use crate::disk::index::{IndexEncoding, IndexInterval};
+    use crate::disk::segment::SegmentOptions;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn sparse_chunks_find_unindexed_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let interval = IndexInterval::Bytes(100);
+        let encoding = IndexEncoding::Compact;
+        let mut chunk = Chunk::new(
+            dir,
+            10,
+            1024,
+            true,
+            interval,
+            encoding,
+            SegmentOptions::default(),
+        )
+        .unwrap();
+        for i in 0..50u8 {
+            let offset = chunk.append(&[i; 30], i).unwrap();
+            assert_eq!(offset, i as u64);
+        }
+
+        // 35 byte records. every 3rd record is indexed
+        assert_eq!(chunk.index.count(), 17);
+        chunk.close().unwrap();
+
+        // sparseness and encoding of existing chunks come from the index header
+        let interval = IndexInterval::Record;
+        let mut chunk = Chunk::new(
+            dir,
+            10,
+            1024,
+            true,
+            interval,
+            IndexEncoding::Wide,
+            SegmentOptions::default(),
+        )
+        .unwrap();
+        assert!(chunk.index.is_sparse());
+        assert_eq!(chunk.index.encoding(), IndexEncoding::Compact);
+        assert_eq!(chunk.count(), 50);
+        for i in 0..50u8 {
+            let (position, len, flags) = chunk.entry(i as u64).unwrap();
+            assert_eq!((position, len, flags), (i as u64 * 35 + 5, 30, i));
+        }
+
+        assert!(chunk.entry(50).is_err());
+        assert_eq!(chunk.readv(4, 100, u64::MAX, false).unwrap(), (140, 105, 3));
+        assert_eq!(
+            chunk.readv(48, 1000, u64::MAX, false).unwrap(),
+            (48 * 35, 70, 2)
+        );
+        assert_eq!(chunk.readv(4, 100, u64::MAX, true).unwrap(), (140, 70, 2));
+        assert_eq!(chunk.readv(4, 10, u64::MAX, true).unwrap(), (140, 0, 0));
+
+        // next record continues from the right offset after reboot
+        assert_eq!(chunk.append(&[50; 30], 0).unwrap(), 50);
+    }
+
+    #[test]
+    fn sparse_chunks_truncate_to_an_unindexed_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let interval = IndexInterval::Records(4);
+        let encoding = IndexEncoding::Wide;
+        let mut chunk = Chunk::new(
+            dir,
+            0,
+            1024,
+            true,
+            interval,
+            encoding,
+            SegmentOptions::default(),
+        )
+        .unwrap();
+        for i in 0..20u8 {
+            chunk.append(&[i; 30], 0).unwrap();
+        }
+
+        chunk.truncate(10).unwrap();
+        assert_eq!((chunk.count(), chunk.index.count()), (10, 3));
+        assert!(chunk.entry(10).is_err());
+        assert_eq!(chunk.append(&[100; 30], 0).unwrap(), 10);
+        chunk.close().unwrap();
+
+        let mut chunk = Chunk::new(
+            dir,
+            0,
+            1024,
+            true,
+            interval,
+            encoding,
+            SegmentOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(chunk.count(), 11);
+        assert_eq!(chunk.entry(10).unwrap(), (10 * 35 + 5, 30, 0));
+    }
+}
@@ -0,0 +1,132 @@
+use std::io;
+
+/// Codec used to compress records before they are written to a segment.
+/// Codec of every record is saved in its index entry. Changing the codec of a
+/// log only affects new records and old records are still readable as long as
+/// their codec is compiled in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Needs `lz4` feature
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// Needs `zstd` feature. Holds compression level
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+/// Codec ids saved in index entries
+pub(crate) const NONE: u8 = 0;
+#[cfg_attr(not(feature = "lz4"), allow(dead_code))]
+pub(crate) const LZ4: u8 = 1;
+#[cfg_attr(not(feature = "zstd"), allow(dead_code))]
+pub(crate) const ZSTD: u8 = 2;
+
+/// Bits of index entry flags used by codec id
+pub(crate) const CODEC_MASK: u8 = 0b11;
+
+impl Compression {
+    /// Compresses the record. Returns codec id along with compressed record.
+    /// Records which don't shrink on compression are saved as is
+    pub(crate) fn compress(&self, record: &[u8]) -> io::Result<Option<(u8, Vec<u8>)>> {
+        let compressed: Option<(u8, Vec<u8>)> = match self {
+            Compression::None => None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some((LZ4, lz4_flex::compress_prepend_size(record))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => Some((ZSTD, zstd::bulk::compress(record, *level)?)),
+        };
+
+        Ok(compressed.filter(|(_, compressed)| compressed.len() < record.len()))
+    }
+}
+
+/// Decompresses a record which is compressed with given codec id. Records
+/// can't be bigger than `max_len` once decompressed, so corrupted records fail
+/// before they are decompressed into more memory than that
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+pub(crate) fn decompress(codec: u8, record: &[u8], max_len: u64) -> io::Result<Vec<u8>> {
+    match codec {
+        NONE => Ok(record.to_vec()),
+        #[cfg(feature = "lz4")]
+        LZ4 => {
+            let len = match record.get(..4) {
+                Some(len) => u32::from_le_bytes([len[0], len[1], len[2], len[3]]),
+                None => return Err(too_large(max_len)),
+            };
+
+            if len as u64 > max_len {
+                return Err(too_large(max_len));
+            }
+
+            lz4_flex::decompress_size_prepended(record)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+        }
+        #[cfg(feature = "zstd")]
+        ZSTD => {
+            use std::io::Read;
+
+            let mut out = Vec::new();
+            zstd::stream::read::Decoder::with_buffer(record)?
+                .take(max_len + 1)
+                .read_to_end(&mut out)?;
+            if out.len() as u64 > max_len {
+                return Err(too_large(max_len));
+            }
+
+            Ok(out)
+        }
+        codec => {
+            let e = format!("Record compressed with unsupported codec {}", codec);
+            Err(io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+}
+
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(dead_code))]
+fn too_large(max_len: u64) -> io::Error {
+    let e = format!("Record decompresses to more than {} bytes", max_len);
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decompress, Compression, NONE};
+
+    #[test]
+    fn uncompressed_records_are_returned_as_is() {
+        let record = vec![1u8; 1024];
+        assert!(Compression::None.compress(&record).unwrap().is_none());
+        assert_eq!(decompress(NONE, &record, 1024).unwrap(), record);
+        assert!(decompress(3, &record, 1024).is_err());
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_round_trip_works() {
+        let record = vec![1u8; 1024];
+        let (codec, compressed) = Compression::Lz4.compress(&record).unwrap().unwrap();
+        assert!(compressed.len() < record.len());
+        assert_eq!(decompress(codec, &compressed, 1024).unwrap(), record);
+        assert!(decompress(codec, &compressed, 1023).is_err());
+
+        // corrupted size prefixes don't allocate their size
+        let mut corrupted = compressed.clone();
+        corrupted[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decompress(codec, &corrupted, 1024).is_err());
+
+        // incompressible records are saved as is
+        assert!(Compression::Lz4.compress(b"abc").unwrap().is_none());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip_works() {
+        let record = vec![1u8; 1024];
+        let (codec, compressed) = Compression::Zstd(3).compress(&record).unwrap().unwrap();
+        assert!(compressed.len() < record.len());
+        assert_eq!(decompress(codec, &compressed, 1024).unwrap(), record);
+        assert!(decompress(codec, &compressed, 1023).is_err());
+    }
+}
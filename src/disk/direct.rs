@@ -0,0 +1,310 @@
+use std::alloc::{self, Layout};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+use std::ptr::NonNull;
+use std::slice;
+
+/// Alignment of buffers, file positions and sizes of direct I/O. Logical block
+/// size of most devices is 512 bytes or 4K
+pub(crate) const BLOCK: usize = 4096;
+
+/// Size of the write buffer
+const BUFFER_SIZE: usize = 128 * 1024;
+
+/// Zeroed heap buffer aligned to `BLOCK`
+struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> AlignedBuf {
+        let layout = Layout::from_size_align(len, BLOCK).unwrap();
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        AlignedBuf { ptr, len }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.len, BLOCK).unwrap();
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), layout) }
+    }
+}
+
+// Buffer is owned memory just like a `Vec`
+unsafe impl Send for AlignedBuf {}
+
+/// File opened with `O_DIRECT` which bypasses the page cache. Writes are
+/// collected in an aligned buffer and written out in whole blocks. Partial last
+/// block is written padded with zeros and rewritten by later writes. Padding
+/// is trimmed by the owner on close as the file doesn't know its logical size
+/// after a flush. Write buffer is only allocated on the first write so that
+/// sealed segments which are only read don't hold one
+pub(crate) struct Direct {
+    file: File,
+    read_only: bool,
+    buf: Option<AlignedBuf>,
+    /// File position of the start of the buffer. Always aligned
+    start: u64,
+    /// Bytes in the buffer
+    len: usize,
+    /// Bytes of the buffer which are already in the file
+    flushed: usize,
+    /// Logical end of the file. Only used to load the write buffer
+    end: u64,
+}
+
+impl Direct {
+    /// Opens the file with direct I/O. Writes continue from `end`
+    pub fn open(path: &Path, end: u64) -> io::Result<Direct> {
+        Direct::with_access(path, end, false)
+    }
+
+    /// Opens the file with direct I/O only for reads. Writes fail
+    pub fn open_read_only(path: &Path) -> io::Result<Direct> {
+        Direct::with_access(path, 0, true)
+    }
+
+    fn with_access(path: &Path, end: u64, read_only: bool) -> io::Result<Direct> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+
+        Ok(Direct {
+            file,
+            read_only,
+            buf: None,
+            start: 0,
+            len: 0,
+            flushed: 0,
+            end,
+        })
+    }
+
+    pub fn write_all(&mut self, mut data: &[u8]) -> io::Result<()> {
+        if self.read_only {
+            return Err(read_only());
+        }
+
+        if self.buf.is_none() {
+            self.load_tail()?;
+        }
+
+        while !data.is_empty() {
+            let buf = self.buf.as_mut().unwrap().as_mut_slice();
+            let n = (BUFFER_SIZE - self.len).min(data.len());
+            buf[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+
+            if self.len == BUFFER_SIZE {
+                self.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes buffered data to the file. Only the partial last block is kept
+    /// in the buffer for the next writes
+    pub fn flush(&mut self) -> io::Result<()> {
+        let buf = match self.buf.as_mut() {
+            Some(buf) if self.len > self.flushed => buf.as_mut_slice(),
+            _ => return Ok(()),
+        };
+
+        let padded = round_up(self.len);
+        buf[self.len..padded].iter_mut().for_each(|byte| *byte = 0);
+        self.file.write_all_at(&buf[..padded], self.start)?;
+
+        let full = self.len - self.len % BLOCK;
+        buf.copy_within(full..self.len, 0);
+        self.start += full as u64;
+        self.len -= full;
+        self.flushed = self.len;
+        Ok(())
+    }
+
+    /// Reads to fill the complete buffer. Writes which aren't flushed yet are
+    /// read from the write buffer
+    pub fn read_exact_at(&self, out: &mut [u8], position: u64) -> io::Result<()> {
+        let end = position + out.len() as u64;
+        let split = match &self.buf {
+            Some(_) if end > self.start => position.max(self.start),
+            _ => end,
+        };
+
+        if let Some(buf) = self.buf.as_ref().filter(|_| split < end) {
+            let (from, to) = ((split - self.start) as usize, (end - self.start) as usize);
+            if to > self.len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+
+            let (file, buffered) = out.split_at_mut((split - position) as usize);
+            buffered.copy_from_slice(&buf.as_slice()[from..to]);
+            return self.read_file(file, position);
+        }
+
+        self.read_file(out, position)
+    }
+
+    #[cfg(test)]
+    pub fn has_buffer(&self) -> bool {
+        self.buf.is_some()
+    }
+
+    /// Reads from the file with aligned reads
+    fn read_file(&self, out: &mut [u8], position: u64) -> io::Result<()> {
+        if out.is_empty() {
+            return Ok(());
+        }
+
+        let begin = position - position % BLOCK as u64;
+        let skip = (position - begin) as usize;
+        let mut aligned = AlignedBuf::new(round_up(skip + out.len()));
+        let aligned = aligned.as_mut_slice();
+
+        // reads at the end of the file return less than asked for
+        let mut filled = 0;
+        while filled < skip + out.len() {
+            match self
+                .file
+                .read_at(&mut aligned[filled..], begin + filled as u64)?
+            {
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                n => filled += n,
+            }
+        }
+
+        out.copy_from_slice(&aligned[skip..skip + out.len()]);
+        Ok(())
+    }
+
+    /// Drops everything after `end`. Writes continue from there
+    pub fn truncate(&mut self, end: u64) -> io::Result<()> {
+        if self.read_only {
+            return Err(read_only());
+        }
+
+        self.flush()?;
+        self.file.set_len(end)?;
+        self.end = end;
+        self.buf = None;
+        Ok(())
+    }
+
+    /// Allocates the write buffer and loads the partial last block into it
+    fn load_tail(&mut self) -> io::Result<()> {
+        self.start = self.end - self.end % BLOCK as u64;
+        self.len = (self.end - self.start) as usize;
+        self.flushed = self.len;
+
+        let mut buf = AlignedBuf::new(BUFFER_SIZE);
+        if self.len > 0 {
+            let mut block = vec![0; self.len];
+            self.read_file(&mut block, self.start)?;
+            buf.as_mut_slice()[..self.len].copy_from_slice(&block);
+        }
+
+        self.buf = Some(buf);
+        Ok(())
+    }
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "Segment is read-only")
+}
+
+fn round_up(len: usize) -> usize {
+    len.div_ceil(BLOCK) * BLOCK
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Direct, BLOCK};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn unaligned_writes_and_reads_work() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"header").unwrap();
+
+        let mut file = Direct::open(&path, 6).unwrap();
+        let mut expected = b"header".to_vec();
+        for i in 0..100u8 {
+            let record = vec![i; 1000 + i as usize];
+            file.write_all(&record).unwrap();
+            expected.extend_from_slice(&record);
+
+            // partial blocks are rewritten
+            if i % 10 == 0 {
+                file.flush().unwrap();
+            }
+        }
+
+        file.flush().unwrap();
+        let len = std::fs::metadata(&path).unwrap().len() as usize;
+        assert_eq!(len, expected.len().div_ceil(BLOCK) * BLOCK);
+
+        let mut out = vec![0; 3000];
+        file.read_exact_at(&mut out, 5000).unwrap();
+        assert_eq!(out, &expected[5000..8000]);
+
+        // writes continue from the logical end after truncation and reopen
+        file.truncate(5000).unwrap();
+        file.write_all(b"tail").unwrap();
+        file.flush().unwrap();
+
+        let file = Direct::open(&path, 5004).unwrap();
+        let mut out = vec![0; 10];
+        file.read_exact_at(&mut out, 4994).unwrap();
+        assert_eq!(&out[..6], &expected[4994..5000]);
+        assert_eq!(&out[6..], b"tail");
+    }
+
+    #[test]
+    fn unflushed_writes_are_read_from_the_buffer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"header").unwrap();
+
+        let mut file = Direct::open(&path, 6).unwrap();
+        let mut expected = b"header".to_vec();
+        for i in 0..10u8 {
+            let record = vec![i; 1000];
+            file.write_all(&record).unwrap();
+            expected.extend_from_slice(&record);
+        }
+
+        let mut out = vec![0; 10000];
+        file.read_exact_at(&mut out, 6).unwrap();
+        assert_eq!(out, &expected[6..]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 6);
+
+        // reads across flushed blocks and the buffer
+        file.flush().unwrap();
+        file.write_all(&[10; 1000]).unwrap();
+        expected.extend_from_slice(&[10; 1000]);
+        let mut out = vec![0; 3000];
+        file.read_exact_at(&mut out, 8000).unwrap();
+        assert_eq!(out, &expected[8000..11000]);
+
+        let mut out = vec![0; 10];
+        assert!(file.read_exact_at(&mut out, 11000).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+#[cfg(feature = "encryption")]
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use std::fmt;
+use std::io;
+
+/// Flag of index entries of encrypted records
+pub(crate) const ENCRYPTED: u8 = 0b100;
+
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+/// Encrypts records with ChaCha20-Poly1305 before they are written to a
+/// segment and authenticates them on reads. Every record is saved as random
+/// nonce followed by the ciphertext. Base offset, relative offset and index
+/// flags of the record are authenticated along with it so that records can't
+/// be moved around in (or between) segments or have their codec changed. Records which aren't encrypted are
+/// rejected unless `allow_plaintext` is set. Needs `encryption` feature
+#[derive(Clone)]
+pub struct Encryption {
+    #[cfg(feature = "encryption")]
+    cipher: ChaCha20Poly1305,
+    allow_plaintext: bool,
+}
+
+impl Encryption {
+    /// Creates an encryption layer with a 256 bit key
+    #[cfg(feature = "encryption")]
+    pub fn new(key: &[u8; 32]) -> Encryption {
+        Encryption {
+            cipher: ChaCha20Poly1305::new(key.into()),
+            allow_plaintext: false,
+        }
+    }
+
+    /// Accepts records which aren't encrypted, e.g records written before the
+    /// log had a key. These records aren't authenticated, so anyone who can
+    /// write to the log directory can change them
+    pub fn allow_plaintext(mut self) -> Encryption {
+        self.allow_plaintext = true;
+        self
+    }
+
+    /// Whether a record which isn't encrypted can be read
+    pub(crate) fn accepts_plaintext(&self) -> bool {
+        self.allow_plaintext
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn encrypt(
+        &self,
+        base_offset: u64,
+        offset: u64,
+        flags: u8,
+        record: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let aad = aad(base_offset, offset, flags);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: record,
+            aad: &aad,
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| io::Error::other("Record encryption failed"))?;
+
+        let mut out = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn decrypt(
+        &self,
+        base_offset: u64,
+        offset: u64,
+        flags: u8,
+        record: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        if record.len() < NONCE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Encrypted record too short",
+            ));
+        }
+
+        let aad = aad(base_offset, offset, flags);
+        let (nonce, ciphertext) = record.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &aad,
+        };
+
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| {
+                let e = format!(
+                    "Record {} of segment {} failed authentication",
+                    offset, base_offset
+                );
+                io::Error::new(io::ErrorKind::InvalidData, e)
+            })
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn encrypt(&self, _: u64, _: u64, _: u8, _: &[u8]) -> io::Result<Vec<u8>> {
+        unreachable!("Encryption can't be created without encryption feature")
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn decrypt(&self, _: u64, _: u64, _: u8, _: &[u8]) -> io::Result<Vec<u8>> {
+        unreachable!("Encryption can't be created without encryption feature")
+    }
+}
+
+/// Keys are never printed
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Encryption { .. }")
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn aad(base_offset: u64, offset: u64, flags: u8) -> [u8; 17] {
+    let mut aad = [0; 17];
+    aad[..8].copy_from_slice(&base_offset.to_be_bytes());
+    aad[8..16].copy_from_slice(&offset.to_be_bytes());
+    aad[16] = flags;
+    aad
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod test {
+    use super::{Encryption, ENCRYPTED};
+
+    #[test]
+    fn records_are_authenticated_with_their_offsets() {
+        let encryption = Encryption::new(&[7; 32]);
+        let record = b"hello timestone commitlog";
+
+        let encrypted = encryption.encrypt(10, 1, ENCRYPTED, record).unwrap();
+        assert_ne!(&encrypted[12..12 + record.len()], &record[..]);
+        let decrypted = encryption.decrypt(10, 1, ENCRYPTED, &encrypted).unwrap();
+        assert_eq!(decrypted, record);
+
+        // moved records, changed flags, wrong keys and tampered records fail
+        assert!(encryption.decrypt(10, 2, ENCRYPTED, &encrypted).is_err());
+        assert!(encryption
+            .decrypt(10, 1, ENCRYPTED | 1, &encrypted)
+            .is_err());
+        assert!(Encryption::new(&[8; 32])
+            .decrypt(10, 1, ENCRYPTED, &encrypted)
+            .is_err());
+
+        let mut tampered = encrypted;
+        tampered[20] ^= 1;
+        assert!(encryption.decrypt(10, 1, ENCRYPTED, &tampered).is_err());
+    }
+}
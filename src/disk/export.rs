@@ -0,0 +1,163 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+
+/// Bytes of records which are read from the log at a time while exporting
+pub(crate) const BATCH_SIZE: u64 = 1024 * 1024;
+
+/// Exported streams start with magic (4 bytes) and format version (1 byte).
+/// Format is documented on `DiskLog::export`
+const MAGIC: &[u8; 4] = b"SEXP";
+
+/// Current version of the stream format. Streams with other versions are rejected
+const VERSION: u8 = 1;
+
+/// Tag of a record. Followed by offset (8 bytes), length (4 bytes) and the record
+const RECORD: u8 = 1;
+
+/// Tag of the end of the stream. Followed by number of records (8 bytes) so that
+/// streams which are cut off aren't mistaken for complete ones
+const END: u8 = 0;
+
+/// Writes records into a stream which doesn't depend on the sizes, index layout,
+/// compression or encryption of the log. Records are saved as they are read.
+/// Integers are big endian. Logs don't keep timestamps or keys of records, so
+/// offsets are the only metadata of a record
+pub(crate) struct Writer<W> {
+    writer: W,
+    count: u64,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut writer: W) -> io::Result<Writer<W>> {
+        writer.write_all(MAGIC)?;
+        writer.write_u8(VERSION)?;
+        Ok(Writer { writer, count: 0 })
+    }
+
+    pub fn write(&mut self, offset: u64, record: &[u8]) -> io::Result<()> {
+        if record.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Record too big to export",
+            ));
+        }
+
+        self.writer.write_u8(RECORD)?;
+        self.writer.write_u64::<BigEndian>(offset)?;
+        self.writer.write_u32::<BigEndian>(record.len() as u32)?;
+        self.writer.write_all(record)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Ends the stream and returns the number of records in it
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.writer.write_u8(END)?;
+        self.writer.write_u64::<BigEndian>(self.count)?;
+        self.writer.flush()?;
+        Ok(self.count)
+    }
+}
+
+/// Reads records of a stream written by `Writer`
+pub(crate) struct Reader<R> {
+    reader: R,
+    count: u64,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut reader: R) -> io::Result<Reader<R>> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not an exported log stream",
+            ));
+        }
+
+        let version = reader.read_u8()?;
+        if version != VERSION {
+            let e = format!("Stream has unsupported format version {}", version);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+
+        Ok(Reader { reader, count: 0 })
+    }
+
+    /// Reads the next record along with its offset. Returns `None` at the end
+    /// of the stream. Streams which end without an end tag are errors
+    pub fn read(&mut self) -> io::Result<Option<(u64, Vec<u8>)>> {
+        match self.reader.read_u8()? {
+            RECORD => {
+                let offset = self.reader.read_u64::<BigEndian>()?;
+                let len = self.reader.read_u32::<BigEndian>()? as u64;
+
+                // lengths of corrupted streams shouldn't allocate upfront
+                let mut record = Vec::new();
+                (&mut self.reader).take(len).read_to_end(&mut record)?;
+                if record.len() as u64 != len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+
+                self.count += 1;
+                Ok(Some((offset, record)))
+            }
+            END => {
+                if self.reader.read_u64::<BigEndian>()? != self.count {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Stream has a wrong number of records",
+                    ));
+                }
+
+                Ok(None)
+            }
+            tag => {
+                let e = format!("Stream has an unknown tag {}", tag);
+                Err(io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Reader, Writer};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn streams_round_trip_and_detect_cut_offs() {
+        let mut stream = Vec::new();
+        let mut writer = Writer::new(&mut stream).unwrap();
+        for i in 0..10u8 {
+            writer.write(100 + i as u64, &vec![i; i as usize]).unwrap();
+        }
+
+        assert_eq!(writer.finish().unwrap(), 10);
+
+        let mut reader = Reader::new(&stream[..]).unwrap();
+        for i in 0..10u8 {
+            let record = reader.read().unwrap().unwrap();
+            assert_eq!(record, (100 + i as u64, vec![i; i as usize]));
+        }
+
+        assert!(reader.read().unwrap().is_none());
+
+        // streams without the end or with missing bytes of a record
+        for end in [stream.len() - 9, stream.len() - 12] {
+            let mut reader = Reader::new(&stream[..end]).unwrap();
+            let end = loop {
+                match reader.read() {
+                    Ok(Some(_)) => continue,
+                    end => break end,
+                }
+            };
+
+            assert!(end.is_err());
+        }
+
+        assert!(Reader::new(&b"SSEG\x01"[..]).is_err());
+        assert!(Reader::new(&b"SEXP\x02"[..]).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Segment and index files start with a header which identifies the file and
+/// the layout of its contents. Magic (4 bytes), format version (1 byte),
+/// encoding (1 byte), flags (2 bytes) and base offset (8 bytes). Meaning of
+/// encoding and flags depends on the type of the file
+pub const HEADER_WIDTH: u64 = 16;
+
+/// Current version of the on-disk format. Files with other versions are rejected
+pub const VERSION: u8 = 1;
+
+pub const INDEX_MAGIC: &[u8; 4] = b"SIDX";
+pub const SEGMENT_MAGIC: &[u8; 4] = b"SSEG";
+
+/// Writes header at the end of an empty file
+pub fn write(
+    file: &mut File,
+    magic: &[u8; 4],
+    encoding: u8,
+    flags: u16,
+    base_offset: u64,
+) -> io::Result<()> {
+    let mut header = [0; HEADER_WIDTH as usize];
+    header[..4].copy_from_slice(magic);
+    header[4] = VERSION;
+    header[5] = encoding;
+    BigEndian::write_u16(&mut header[6..8], flags);
+    BigEndian::write_u64(&mut header[8..], base_offset);
+    file.write_all(&header)
+}
+
+/// Reads header of a file and returns its encoding and flags. Returns `None`
+/// when the file doesn't start with the magic, e.g legacy files written before
+/// headers existed. Headers with an unknown version or a base offset which
+/// doesn't match the file name are errors
+pub fn read(file: &mut File, magic: &[u8; 4], base_offset: u64) -> io::Result<Option<(u8, u16)>> {
+    if file.metadata()?.len() < HEADER_WIDTH {
+        return Ok(None);
+    }
+
+    let mut header = [0; HEADER_WIDTH as usize];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header)?;
+    if &header[..4] != magic {
+        return Ok(None);
+    }
+
+    let name = String::from_utf8_lossy(magic);
+    if header[4] != VERSION {
+        let e = format!(
+            "File {} ({}) has unsupported format version {}",
+            base_offset, name, header[4]
+        );
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+
+    if BigEndian::read_u64(&header[8..]) != base_offset {
+        let e = format!(
+            "File {} ({}) has a wrong base offset in header",
+            base_offset, name
+        );
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+
+    Ok(Some((header[5], BigEndian::read_u16(&header[6..8]))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read, write, INDEX_MAGIC, SEGMENT_MAGIC};
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    #[test]
+    fn headers_are_validated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .unwrap();
+
+        assert_eq!(read(&mut file, INDEX_MAGIC, 10).unwrap(), None);
+        write(&mut file, INDEX_MAGIC, 1, 0b10, 10).unwrap();
+        assert_eq!(read(&mut file, INDEX_MAGIC, 10).unwrap(), Some((1, 0b10)));
+
+        // other file types, base offsets and versions
+        assert_eq!(read(&mut file, SEGMENT_MAGIC, 10).unwrap(), None);
+        assert!(read(&mut file, INDEX_MAGIC, 11).is_err());
+
+        let mut header = std::fs::read(&path).unwrap();
+        header[4] = 2;
+        file.set_len(0).unwrap();
+        file.write_all(&header).unwrap();
+        assert!(read(&mut file, INDEX_MAGIC, 10).is_err());
+    }
+}
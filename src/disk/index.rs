@@ -1,63 +1,240 @@
+use super::header::{self, HEADER_WIDTH, INDEX_MAGIC};
+use super::segment;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use memmap::MmapMut;
-use std::fs::{File, OpenOptions};
+use memmap::{MmapMut, MmapOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 
-const POS_WIDTH: u64 = 8;
-const LEN_WIDTH: u64 = 8;
-const ENTRY_WIDTH: u64 = POS_WIDTH + LEN_WIDTH;
+/// Header flag of sparse indexes
+const SPARSE: u16 = 0b1;
+
+/// Space for entries which active indexes start with. Indexes grow as entries
+/// are written till they reach their maximum size
+const INITIAL_SIZE: u64 = 4096;
+
+/// How entries of an index are laid out on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexEncoding {
+    /// 8 byte position and 8 byte length. Top byte of the length holds flags
+    /// of the record (e.g compression codec)
+    #[default]
+    Wide,
+    /// 4 byte position and 4 byte length. Top 4 bits of the length hold flags of
+    /// the record. Halves the size of the index but limits segments to 4GB and
+    /// records to 256MB
+    Compact,
+}
+
+impl IndexEncoding {
+    fn id(&self) -> u8 {
+        match self {
+            IndexEncoding::Wide => 0,
+            IndexEncoding::Compact => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<IndexEncoding> {
+        match id {
+            0 => Some(IndexEncoding::Wide),
+            1 => Some(IndexEncoding::Compact),
+            _ => None,
+        }
+    }
+
+    /// Width of position and length fields of an entry
+    fn field_width(&self) -> u64 {
+        match self {
+            IndexEncoding::Wide => 8,
+            IndexEncoding::Compact => 4,
+        }
+    }
+
+    fn entry_width(&self) -> u64 {
+        2 * self.field_width()
+    }
+
+    /// Largest value of a field
+    fn field_max(&self) -> u64 {
+        u64::MAX >> (64 - 8 * self.field_width())
+    }
+
+    /// Bits of the length field below the flags of the record
+    fn flags_shift(&self) -> u64 {
+        match self {
+            IndexEncoding::Wide => 56,
+            IndexEncoding::Compact => 28,
+        }
+    }
+
+    /// Largest record length which can be indexed
+    pub fn max_len(&self) -> u64 {
+        (1 << self.flags_shift()) - 1
+    }
+
+    /// Largest record position which can be indexed
+    pub fn max_position(&self) -> u64 {
+        self.field_max()
+    }
+}
 
+/// How often records of a segment are indexed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexInterval {
+    /// Every record is indexed
+    #[default]
+    Record,
+    /// A record is indexed when the segment has grown by these many bytes
+    /// since the last indexed record
+    Bytes(u64),
+    /// Every n-th record is indexed
+    Records(u64),
+}
+
+impl IndexInterval {
+    pub fn is_sparse(&self) -> bool {
+        *self != IndexInterval::Record
+    }
+}
+
+/// Index of a segment. Dense indexes have position and length (along with flags)
+/// of every record. Sparse indexes only have position and relative offset of
+/// some of the records. Rest of the records are found by scanning record headers
+/// in the segment from the nearest indexed record
 pub struct Index {
     base_offset: u64,
+    sparse: bool,
+    encoding: IndexEncoding,
+    /// Size of the file header. 0 for legacy indexes
+    header_width: u64,
     file: File,
+    /// Sealed indexes are opened read-only and mapped copy-on-write
+    read_only: bool,
     mmap: MmapMut,
+    /// Size of the entries
     pub(crate) size: u64,
     pub(crate) max_size: u64,
 }
 
 impl Index {
+    /// Dense index with wide entries
+    #[cfg(test)]
     pub fn new<P: AsRef<Path>>(
         dir: P,
         base_offset: u64,
         max_size: u64,
         active: bool,
+    ) -> io::Result<Index> {
+        Index::open(
+            dir,
+            base_offset,
+            max_size,
+            active,
+            IndexEncoding::Wide,
+            false,
+        )
+    }
+
+    /// Opens the index of a chunk. Encoding and sparseness are only used for new
+    /// indexes. Existing indexes are read as described by their header. Indexes
+    /// which aren't active are opened read-only unless they are empty
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        base_offset: u64,
+        max_size: u64,
+        active: bool,
+        encoding: IndexEncoding,
+        sparse: bool,
+    ) -> io::Result<Index> {
+        let index = Index::map(dir, base_offset, max_size, active, encoding, sparse)?;
+        index.verify()?;
+        Ok(index)
+    }
+
+    /// Opens an existing index read-only without checking it. Indexes which
+    /// are still being written have trailing 0s which should be trimmed
+    pub fn open_read_only<P: AsRef<Path>>(dir: P, base_offset: u64) -> io::Result<Index> {
+        let file_path = dir.as_ref().join(format!("{:020}.index", base_offset));
+        if fs::metadata(&file_path)?.len() == 0 {
+            let e = format!("Index {} is empty", base_offset);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+
+        Index::map(dir, base_offset, 0, false, IndexEncoding::default(), false)
+    }
+
+    fn map<P: AsRef<Path>>(
+        dir: P,
+        base_offset: u64,
+        max_size: u64,
+        active: bool,
+        encoding: IndexEncoding,
+        sparse: bool,
     ) -> io::Result<Index> {
         let file_name = format!("{:020}.index", base_offset);
         let file_path: PathBuf = dir.as_ref().join(file_name);
-        let verify = file_path.exists();
 
-        let file = OpenOptions::new()
+        let existing = fs::metadata(&file_path).map_or(0, |metadata| metadata.len());
+        let read_only = !active && existing > 0;
+        let mut file = OpenOptions::new()
             .read(true)
-            .append(true)
-            .create(true)
+            .append(!read_only)
+            .create(!read_only)
             .open(&file_path)?;
         let metadata = file.metadata()?;
-        let size = metadata.len() as u64;
+        let size = metadata.len();
+
+        // New (or empty) indexes get a header. Existing indexes are read as described
+        // by their header. Indexes written before headers existed start with the
+        // position of the first record, which is always 0, and are legacy wide indexes
+        let (encoding, sparse, header_width) = if size == 0 {
+            let flags = if sparse { SPARSE } else { 0 };
+            header::write(&mut file, INDEX_MAGIC, encoding.id(), flags, base_offset)?;
+            (encoding, sparse, HEADER_WIDTH)
+        } else {
+            match header::read(&mut file, INDEX_MAGIC, base_offset)? {
+                Some((id, flags)) => {
+                    let encoding = IndexEncoding::from_id(id).ok_or_else(|| {
+                        let e = format!("Index {} has unsupported encoding {}", base_offset, id);
+                        io::Error::new(io::ErrorKind::InvalidData, e)
+                    })?;
+
+                    (encoding, flags & SPARSE != 0, HEADER_WIDTH)
+                }
+                None => (IndexEncoding::Wide, sparse, 0),
+            }
+        };
 
         // truncate and mmap the file
         // Old segment indexes are properly closed which shrinks the size of index file form maximum size
         // Old segment indexes are immutable and hence we freeze the size to file size.
-        // For active segments, we set the size to max to be able to append more segment information
-        if active {
-            file.set_len(max_size)?;
+        // For active segments, we reserve some space to append more segment information
+        // and grow the file when it fills up
+        let len = file.metadata()?.len();
+        let mmap = if read_only {
+            unsafe { MmapOptions::new().map_copy(&file)? }
         } else {
-            file.set_len(size)?;
-        }
+            if active {
+                file.set_len(len.max(header_width + max_size.min(INITIAL_SIZE)))?;
+            } else {
+                file.set_len(len)?;
+            }
+
+            unsafe { MmapMut::map_mut(&file)? }
+        };
 
-        let mmap = unsafe { MmapMut::map_mut(&file)? };
         let index = Index {
             base_offset,
+            sparse,
+            encoding,
+            header_width,
             file,
+            read_only,
             mmap,
-            size,
+            size: len - header_width,
             max_size,
         };
 
-        if verify {
-            index.verify()?;
-        }
-
         Ok(index)
     }
 
@@ -65,9 +242,59 @@ impl Index {
         self.base_offset
     }
 
+    pub fn is_sparse(&self) -> bool {
+        self.sparse
+    }
+
+    /// Whether the index was written before files had headers
+    pub fn is_legacy(&self) -> bool {
+        self.header_width == 0
+    }
+
+    #[cfg(test)]
+    pub fn encoding(&self) -> IndexEncoding {
+        self.encoding
+    }
+
+    /// Size of the file without the space reserved for new entries. Includes
+    /// the file header
+    pub fn file_size(&self) -> u64 {
+        self.header_width + self.size
+    }
+
     /// Number of entries
     pub fn count(&self) -> u64 {
-        self.size / ENTRY_WIDTH
+        self.size / self.encoding.entry_width()
+    }
+
+    /// Whether the index has grown to its maximum size. Writes still work on a full
+    /// index but owner is expected to start a new index
+    pub fn is_full(&self) -> bool {
+        self.size + self.encoding.entry_width() > self.max_size
+    }
+
+    /// Drops trailing entries which aren't written yet or whose records aren't
+    /// completely in the segment yet. Only the first record of a segment can be
+    /// at position 0. For indexes of logs which are being written by others
+    pub fn trim(&mut self, segment_size: u64) -> io::Result<()> {
+        let mut count = self.count();
+        while count > 0 {
+            let (position, len, _) = self.read_with_flags(count - 1)?;
+            let end = if self.sparse {
+                position + segment::HEADER_WIDTH
+            } else {
+                position + len
+            };
+            let written = count == 1 || position != 0;
+            if written && end <= segment_size && segment_size > 0 {
+                break;
+            }
+
+            count -= 1;
+        }
+
+        self.size = count * self.encoding.entry_width();
+        Ok(())
     }
 
     /// Index files which aren't closed will contains zeros as the mmap file wouldn't be truncated
@@ -75,14 +302,17 @@ impl Index {
     /// Facilitates easier intuition of logic & segment appends won't return wrong offset due to
     /// incorrect size. We can just do size based segment jumps and use ? for error handling
     fn verify(&self) -> io::Result<()> {
+        // Properly closed empty index. E.g a chunk which is closed right after it's created
         let count = self.count();
         if count == 0 {
-            let e = format!("Index {} should contain some data", self.base_offset);
-            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            return Ok(());
         }
 
+        // Only the first record of a segment can be at position 0. First entry of
+        // a sparse index is for relative offset 0
         let (position, len) = self.read(count - 1)?;
-        if position == 0 || len == 0 {
+        let empty = if self.sparse { count > 1 } else { true };
+        if (position == 0 && count > 1) || (len == 0 && empty) {
             let e = format!(
                 "Index {} has trailing 0s. Index corrupted",
                 self.base_offset
@@ -93,27 +323,72 @@ impl Index {
         Ok(())
     }
 
-    pub fn write(&mut self, pos: u64, len: u64) -> io::Result<()> {
-        if self.size + ENTRY_WIDTH > self.max_size {
-            return Err(io::Error::new(io::ErrorKind::Other, "Index full"));
+    /// Writes position and size of a record along with its flags. Sparse indexes
+    /// write relative offset of the record instead of its size and have no flags
+    pub fn write(&mut self, pos: u64, len: u64, flags: u8) -> io::Result<()> {
+        let encoding = self.encoding;
+        let max_len = if self.sparse {
+            encoding.field_max()
+        } else {
+            encoding.max_len()
+        };
+
+        if len > max_len || flags as u64 > encoding.field_max() >> encoding.flags_shift() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Record too big to index",
+            ));
+        }
+
+        if pos > encoding.max_position() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Record position doesn't fit in the index",
+            ));
+        }
+
+        let width = encoding.entry_width();
+        if self.header_width + self.size + width > self.mmap.len() as u64 {
+            self.grow()?;
         }
 
-        let start = self.size as usize;
-        let end = start + POS_WIDTH as usize;
-        let mut buf = &mut self.mmap.as_mut()[start..end];
-        buf.write_u64::<BigEndian>(pos)?;
+        let len = (flags as u64) << encoding.flags_shift() | len;
+        let start = (self.header_width + self.size) as usize;
+        let mut buf = &mut self.mmap.as_mut()[start..start + width as usize];
+        match encoding {
+            IndexEncoding::Wide => {
+                buf.write_u64::<BigEndian>(pos)?;
+                buf.write_u64::<BigEndian>(len)?;
+            }
+            IndexEncoding::Compact => {
+                buf.write_u32::<BigEndian>(pos as u32)?;
+                buf.write_u32::<BigEndian>(len as u32)?;
+            }
+        }
 
-        let start = end;
-        let end = start + LEN_WIDTH as usize;
-        let mut buf = &mut self.mmap.as_mut()[start..end];
-        buf.write_u64::<BigEndian>(len)?;
+        self.size += width;
+        Ok(())
+    }
 
-        self.size += ENTRY_WIDTH;
+    /// Doubles the size of the file, up to the maximum size, and remaps it
+    fn grow(&mut self) -> io::Result<()> {
+        let needed = self.header_width + self.size + self.encoding.entry_width();
+        let max_len = self.header_width + self.max_size;
+        let len = (self.mmap.len() as u64 * 2).min(max_len).max(needed);
+        self.mmap.flush()?;
+        self.file.set_len(len)?;
+        self.mmap = unsafe { MmapMut::map_mut(&self.file)? };
         Ok(())
     }
 
     /// Reads an offset from the index and returns segment record's position and size
     pub fn read(&self, offset: u64) -> io::Result<(u64, u64)> {
+        let (position, len, _) = self.read_with_flags(offset)?;
+        Ok((position, len))
+    }
+
+    /// Reads an offset from the index and returns segment record's position, size and flags
+    pub fn read_with_flags(&self, offset: u64) -> io::Result<(u64, u64, u8)> {
         if self.size == 0 {
             return Err(io::Error::new(
                 io::ErrorKind::UnexpectedEof,
@@ -122,36 +397,81 @@ impl Index {
         }
 
         // entry of the target offset
-        let entry_position = offset * ENTRY_WIDTH;
+        let width = self.encoding.entry_width();
+        let entry_position = offset * width;
 
         // reading at invalid postion from a file is implementation dependent. handle this explicitly
         // https://doc.rust-lang.org/std/io/trait.Seek.html#tymethod.seek
-        if self.size < entry_position + ENTRY_WIDTH {
+        if self.size < entry_position + width {
             return Err(io::Error::new(
                 io::ErrorKind::UnexpectedEof,
                 "Reading at an invalid offset",
             ));
         }
 
-        // read position
-        let start = entry_position as usize;
-        let end = start + POS_WIDTH as usize;
-        let mut buf = &self.mmap[start..end];
-        let position = buf.read_u64::<BigEndian>()?;
+        let start = (self.header_width + entry_position) as usize;
+        let mut buf = &self.mmap[start..start + width as usize];
+        let (position, len) = match self.encoding {
+            IndexEncoding::Wide => (buf.read_u64::<BigEndian>()?, buf.read_u64::<BigEndian>()?),
+            IndexEncoding::Compact => (
+                buf.read_u32::<BigEndian>()? as u64,
+                buf.read_u32::<BigEndian>()? as u64,
+            ),
+        };
+
+        if self.sparse {
+            return Ok((position, len, 0));
+        }
+
+        let flags = (len >> self.encoding.flags_shift()) as u8;
+        Ok((position, len & self.encoding.max_len(), flags))
+    }
 
-        // read len
-        let start = end;
-        let end = start + LEN_WIDTH as usize;
-        let mut buf = &self.mmap[start..end];
-        let len = buf.read_u64::<BigEndian>()?;
+    /// Finds the last entry of a sparse index at or before the given relative
+    /// offset. Returns relative offset and position of the entry
+    pub fn lookup(&self, offset: u64) -> io::Result<(u64, u64)> {
+        if self.size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "No entries in index",
+            ));
+        }
 
-        Ok((position, len))
+        // Entries are sorted by relative offset. Find the first entry after the offset
+        let (mut low, mut high) = (0, self.count());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let (_, entry_offset) = self.read(mid)?;
+            if entry_offset <= offset {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        if low == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Sparse index doesn't start at relative offset 0",
+            ));
+        }
+
+        let (position, entry_offset) = self.read(low - 1)?;
+        Ok((entry_offset, position))
     }
 
     /// Returns starting position, size required to fit 'n' records, n (count)
     /// Total size of records might cross the provided boundary. Use returned size
-    /// for allocating the buffer
-    pub fn readv(&self, offset: u64, size: u64) -> io::Result<(u64, u64, u64)> {
+    /// for allocating the buffer. At most `max_count` records are returned.
+    /// `strict` leaves out the record which crosses the boundary. Count is 0
+    /// when the first record itself doesn't fit
+    pub fn readv(
+        &self,
+        offset: u64,
+        size: u64,
+        max_count: u64,
+        strict: bool,
+    ) -> io::Result<(u64, u64, u64)> {
         let mut count = 0;
         let mut current_size = 0;
         let mut next_offset = offset;
@@ -163,11 +483,15 @@ impl Index {
 
         // all the errors here are soft failures which are just used to break the loop
         loop {
+            if strict && current_size + last_record_size > size {
+                break;
+            }
+
             // size reached. include the last record even though it crosses boundary
             current_size += last_record_size;
             next_offset += 1;
             count += 1;
-            if current_size >= size {
+            if current_size >= size || count >= max_count {
                 break;
             }
 
@@ -183,23 +507,46 @@ impl Index {
         Ok((start_position, current_size, count))
     }
 
+    /// Drops entries after the first `count` entries. Dropped entries are zeroed
+    /// so that they aren't picked up if the index isn't closed properly
+    pub fn truncate(&mut self, count: u64) {
+        let size = count.min(self.count()) * self.encoding.entry_width();
+        let start = (self.header_width + size) as usize;
+        let end = (self.header_width + self.size) as usize;
+        self.mmap[start..end].iter_mut().for_each(|byte| *byte = 0);
+        self.size = size;
+    }
+
+    /// Writes entries to the disk and waits for them
+    pub fn sync(&self) -> io::Result<()> {
+        if self.read_only {
+            return self.file.sync_data();
+        }
+
+        self.mmap.flush()
+    }
+
     pub fn close(&mut self) -> io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
         self.mmap.flush()?;
         self.file.flush()?;
-        self.file.set_len(self.size)?;
+        self.file.set_len(self.header_width + self.size)?;
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Index;
+    use super::{Index, IndexEncoding};
     use tempfile::tempdir;
 
     fn write_entries(index: &mut Index, entries: Vec<(u64, u64)>) {
         for (offset, (position, len)) in entries.into_iter().enumerate() {
             let offset = offset as u64;
-            index.write(position, len).unwrap();
+            index.write(position, len, 0).unwrap();
             let (p, l) = index.read(offset).unwrap();
             assert_eq!(len, l);
             assert_eq!(position, p);
@@ -245,7 +592,7 @@ mod test {
         write_entries(&mut index, entries);
         index.close().unwrap();
 
-        let (position, size, count) = index.readv(1, 1024).unwrap();
+        let (position, size, count) = index.readv(1, 1024, u64::MAX, false).unwrap();
         assert_eq!(position, 100);
         assert_eq!(size, 1000);
         assert_eq!(count, 4);
@@ -255,9 +602,127 @@ mod test {
         index.close().unwrap();
 
         // read less than size of a single record
-        let (offset, size, count) = index.readv(2, 100).unwrap();
+        let (offset, size, count) = index.readv(2, 100, u64::MAX, false).unwrap();
         assert_eq!(offset, 200);
         assert_eq!(size, 600);
         assert_eq!(count, 1);
+
+        // strict reads leave out the record which crosses the boundary
+        assert_eq!(index.readv(0, 900, u64::MAX, true).unwrap(), (0, 800, 3));
+        assert_eq!(index.readv(2, 100, u64::MAX, true).unwrap(), (200, 0, 0));
+    }
+
+    #[test]
+    fn full_index_grows_on_writes() {
+        let path = tempdir().unwrap();
+
+        // 8 entries
+        let mut index = Index::new(&path, 0, 128, true).unwrap();
+        for i in 0..20 {
+            assert_eq!(index.is_full(), i >= 8);
+            index.write(i * 100, 100, 0).unwrap();
+        }
+
+        for i in 0..20 {
+            assert_eq!(index.read(i).unwrap(), (i * 100, 100));
+        }
+
+        index.close().unwrap();
+        let index = Index::new(&path, 0, 128, false).unwrap();
+        assert_eq!(index.count(), 20);
+        assert_eq!(index.read(19).unwrap(), (1900, 100));
+    }
+
+    #[test]
+    fn sparse_lookups_find_the_nearest_entry() {
+        let path = tempdir().unwrap();
+        let mut index = Index::open(&path, 0, 1024, true, IndexEncoding::Wide, true).unwrap();
+        assert!(index.lookup(0).is_err());
+
+        // (position, relative offset) of every 10th record
+        for i in 0..5 {
+            index.write(i * 1000, i * 10, 0).unwrap();
+        }
+
+        assert_eq!(index.lookup(0).unwrap(), (0, 0));
+        assert_eq!(index.lookup(9).unwrap(), (0, 0));
+        assert_eq!(index.lookup(10).unwrap(), (10, 1000));
+        assert_eq!(index.lookup(35).unwrap(), (30, 3000));
+        assert_eq!(index.lookup(1000).unwrap(), (40, 4000));
+        index.close().unwrap();
+
+        let index = Index::open(&path, 0, 1024, false, IndexEncoding::Wide, true).unwrap();
+        assert_eq!(index.count(), 5);
+
+        // single entry sparse indexes are valid
+        let path = tempdir().unwrap();
+        let mut index = Index::open(&path, 0, 1024, true, IndexEncoding::Wide, true).unwrap();
+        index.write(0, 0, 0).unwrap();
+        index.close().unwrap();
+        let index = Index::open(&path, 0, 1024, true, IndexEncoding::Wide, true).unwrap();
+        assert_eq!(index.lookup(3).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn flags_dont_change_length_of_the_record() {
+        let path = tempdir().unwrap();
+        let mut index = Index::new(&path, 0, 1024, true).unwrap();
+
+        index.write(0, 100, 1).unwrap();
+        index.write(100, 200, 0).unwrap();
+        assert_eq!(index.read_with_flags(0).unwrap(), (0, 100, 1));
+        assert_eq!(index.read_with_flags(1).unwrap(), (100, 200, 0));
+        assert_eq!(index.read(0).unwrap(), (0, 100));
+
+        let (position, size, count) = index.readv(0, 1024, u64::MAX, false).unwrap();
+        assert_eq!((position, size, count), (0, 300, 2));
+    }
+
+    #[test]
+    fn compact_indexes_are_half_the_size() {
+        let path = tempdir().unwrap();
+        let mut index = Index::open(&path, 10, 1024, true, IndexEncoding::Compact, false).unwrap();
+
+        index.write(0, 100, 0b101).unwrap();
+        index.write(100, (1 << 28) - 1, 0).unwrap();
+        assert!(index.write(200, 1 << 28, 0).is_err());
+        assert!(index.write(1 << 32, 100, 0).is_err());
+        index.close().unwrap();
+
+        let file = std::fs::metadata(path.path().join(format!("{:020}.index", 10))).unwrap();
+        assert_eq!(file.len(), 16 + 2 * 8);
+
+        // header decides the encoding of existing indexes
+        let index = Index::new(&path, 10, 1024, false).unwrap();
+        assert_eq!(index.encoding(), IndexEncoding::Compact);
+        assert_eq!(index.count(), 2);
+        assert_eq!(index.read_with_flags(0).unwrap(), (0, 100, 0b101));
+        assert_eq!(index.read(1).unwrap(), (100, (1 << 28) - 1));
+    }
+
+    #[test]
+    fn legacy_indexes_without_header_still_open() {
+        let path = tempdir().unwrap();
+        let mut legacy = Vec::new();
+        for (position, len) in [(0u64, 100u64), (100, 200), (300, 50)] {
+            legacy.extend_from_slice(&position.to_be_bytes());
+            legacy.extend_from_slice(&len.to_be_bytes());
+        }
+
+        let file = path.path().join(format!("{:020}.index", 0));
+        std::fs::write(&file, legacy).unwrap();
+
+        let mut index = Index::open(&path, 0, 1024, true, IndexEncoding::Compact, false).unwrap();
+        assert_eq!(index.encoding(), IndexEncoding::Wide);
+        assert_eq!(index.count(), 3);
+        assert_eq!(index.read(2).unwrap(), (300, 50));
+
+        // appends continue in the legacy format
+        index.write(350, 10, 0).unwrap();
+        index.close().unwrap();
+        assert_eq!(std::fs::metadata(&file).unwrap().len(), 4 * 16);
+
+        let index = Index::new(&path, 0, 1024, false).unwrap();
+        assert_eq!(index.read(3).unwrap(), (350, 10));
     }
 }
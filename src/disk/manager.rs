@@ -0,0 +1,425 @@
+use super::{Config, DiskLog};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// A topic known to the manager. Logs of idle topics are closed to release
+/// file descriptors and mmaps. Size of a closed log is remembered so that the
+/// disk budget can be enforced without opening every log
+struct Topic {
+    log: Option<DiskLog>,
+    size: u64,
+    last_access: Instant,
+}
+
+/// Manages many named logs under a root directory. Every topic is a `DiskLog`
+/// in `root/<topic>`. Logs are created when they are first written to, all the
+/// logs together are kept under `max_disk_size` by deleting oldest chunks of
+/// the biggest topics and at most `max_open_logs` logs are kept open
+pub struct LogManager {
+    dir: PathBuf,
+    config: Config,
+    max_disk_size: u64,
+    max_open_logs: usize,
+    /// Total size of all the topics
+    size: u64,
+    topics: HashMap<String, Topic>,
+}
+
+impl LogManager {
+    /// Creates a manager and registers all the topics which already exist
+    /// under `dir`. Logs of these topics are opened on first access
+    pub fn new<P: Into<PathBuf>>(
+        dir: P,
+        config: Config,
+        max_disk_size: u64,
+        max_open_logs: usize,
+    ) -> io::Result<LogManager> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        if max_open_logs == 0 {
+            panic!("at least one log should be allowed to be open")
+        }
+
+        let mut topics = HashMap::new();
+        let mut size = 0;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.to_owned(),
+                None => {
+                    warn!("Ignoring topic directory with invalid name {:?}", path);
+                    continue;
+                }
+            };
+
+            let topic_size = dir_size(&path)?;
+            let topic = Topic {
+                log: None,
+                size: topic_size,
+                last_access: Instant::now(),
+            };
+
+            size += topic_size;
+            topics.insert(name, topic);
+        }
+
+        let manager = LogManager {
+            dir,
+            config,
+            max_disk_size,
+            max_open_logs,
+            size,
+            topics,
+        };
+
+        Ok(manager)
+    }
+
+    /// Names of all the topics
+    pub fn topics(&self) -> Vec<String> {
+        self.topics.keys().cloned().collect()
+    }
+
+    /// Total bytes used by all the topics
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Number of logs which are currently open
+    pub fn open_logs(&self) -> usize {
+        self.topics.values().filter(|t| t.log.is_some()).count()
+    }
+
+    /// Appends record to the topic's log creating the log if necessary and
+    /// enforces disk budget. Returns base offset and relative offset of the record
+    pub fn append(&mut self, topic: &str, record: &[u8]) -> io::Result<(u64, u64)> {
+        let offsets = self.with_log(topic, true, |log| log.append(record))?;
+        self.apply_retention()?;
+        Ok(offsets)
+    }
+
+    /// Reads a record of the topic. See `DiskLog::read`
+    pub fn read(&mut self, topic: &str, base_offset: u64, offset: u64) -> io::Result<Vec<u8>> {
+        self.with_log(topic, false, |log| log.read(base_offset, offset))
+    }
+
+    /// Reads multiple records of the topic. See `DiskLog::readv`
+    pub fn readv(
+        &mut self,
+        topic: &str,
+        base_offset: u64,
+        relative_offset: u64,
+        size: u64,
+    ) -> io::Result<(u64, u64, u64, Vec<u8>)> {
+        self.with_log(topic, false, |log| {
+            log.readv(base_offset, relative_offset, size)
+        })
+    }
+
+    /// Closes logs which weren't accessed in the last `idle` duration
+    pub fn close_idle(&mut self, idle: Duration) -> io::Result<()> {
+        let now = Instant::now();
+        for topic in self.topics.values_mut() {
+            if now.duration_since(topic.last_access) >= idle {
+                topic.close()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn close_all(&mut self) -> io::Result<()> {
+        for topic in self.topics.values_mut() {
+            topic.close()?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the topic and all its data
+    pub fn remove(&mut self, topic: &str) -> io::Result<()> {
+        if let Some(mut t) = self.topics.remove(topic) {
+            t.close()?;
+            self.size -= t.size;
+            fs::remove_dir_all(self.dir.join(topic))?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs `f` on the log of the topic after opening it and keeps size
+    /// accounting of the topic up to date
+    fn with_log<T, F>(&mut self, topic: &str, create: bool, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut DiskLog) -> io::Result<T>,
+    {
+        if !self.topics.contains_key(topic) {
+            if !create {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "Invalid topic"));
+            }
+
+            validate(topic)?;
+            let t = Topic {
+                log: None,
+                size: 0,
+                last_access: Instant::now(),
+            };
+
+            self.topics.insert(topic.to_owned(), t);
+        }
+
+        let is_open = self.topics[topic].log.is_some();
+        if !is_open {
+            if self.open_logs() >= self.max_open_logs {
+                self.close_least_recently_used()?;
+            }
+
+            let log = DiskLog::with_config(self.dir.join(topic), self.config.scope(topic)?)?;
+            let t = self.topics.get_mut(topic).unwrap();
+            self.size = self.size - t.size + log.size();
+            t.size = log.size();
+            t.log = Some(log);
+        }
+
+        let t = self.topics.get_mut(topic).unwrap();
+        t.last_access = Instant::now();
+        let log = t.log.as_mut().unwrap();
+        let out = f(log);
+
+        let size = log.size();
+        self.size = self.size - t.size + size;
+        t.size = size;
+        out
+    }
+
+    fn close_least_recently_used(&mut self) -> io::Result<()> {
+        let lru = self
+            .topics
+            .values_mut()
+            .filter(|t| t.log.is_some())
+            .min_by_key(|t| t.last_access);
+
+        if let Some(topic) = lru {
+            topic.close()?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes oldest chunks of the biggest topics till total size is
+    /// within the disk budget. Active chunks are never deleted
+    fn apply_retention(&mut self) -> io::Result<()> {
+        let mut skip = Vec::new();
+        while self.size > self.max_disk_size {
+            let victim = self
+                .topics
+                .iter()
+                .filter(|(name, _)| !skip.contains(*name))
+                .max_by_key(|(_, t)| t.size)
+                .map(|(name, _)| name.clone());
+
+            let victim = match victim {
+                Some(v) => v,
+                None => {
+                    warn!("Disk budget exceeded but there are no chunks to delete");
+                    break;
+                }
+            };
+
+            let removed = self.with_log(&victim, false, |log| {
+                if log.segment_count() == 1 {
+                    return Ok(false);
+                }
+
+                let head = log.head();
+                log.remove(head)?;
+                Ok(true)
+            })?;
+
+            if !removed {
+                skip.push(victim);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Topic {
+    fn close(&mut self) -> io::Result<()> {
+        if let Some(mut log) = self.log.take() {
+            log.close_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Topic names are used as directory names
+fn validate(topic: &str) -> io::Result<()> {
+    if topic.is_empty() || topic == "." || topic == ".." || topic.contains(['/', '\\']) {
+        let e = format!("Invalid topic name {:?}", topic);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+    }
+
+    Ok(())
+}
+
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::LogManager;
+    use crate::disk::Config;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    fn config() -> Config {
+        Config {
+            max_index_size: 100 * 16,
+            max_segment_size: 10 * 1024,
+            max_segments: 100,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn topics_are_created_lazily_and_reopened_on_startup() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let mut manager = LogManager::new(dir, config(), 10 * 1024 * 1024, 10).unwrap();
+        assert!(manager.topics().is_empty());
+        assert!(manager.read("a", 0, 0).is_err());
+
+        let mut payload = vec![0u8; 1024];
+        for i in 0..15 {
+            payload[0] = i;
+            manager.append("a", &payload).unwrap();
+            manager.append("b", &payload).unwrap();
+        }
+
+        manager.close_all().unwrap();
+
+        let mut manager = LogManager::new(dir, config(), 10 * 1024 * 1024, 10).unwrap();
+        let mut topics = manager.topics();
+        topics.sort();
+        assert_eq!(topics, vec!["a".to_owned(), "b".to_owned()]);
+        assert_eq!(manager.open_logs(), 0);
+
+        let data = manager.read("b", 10, 4).unwrap();
+        assert_eq!(data[0], 14);
+        assert_eq!(manager.open_logs(), 1);
+    }
+
+    #[test]
+    fn least_recently_used_logs_are_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let mut manager = LogManager::new(dir, config(), 10 * 1024 * 1024, 2).unwrap();
+        let payload = vec![1u8; 1024];
+        for topic in ["a", "b", "c", "d"].iter() {
+            manager.append(topic, &payload).unwrap();
+            assert!(manager.open_logs() <= 2);
+        }
+
+        // closed topics are transparently reopened
+        let data = manager.read("a", 0, 0).unwrap();
+        assert_eq!(data, payload);
+        assert_eq!(manager.open_logs(), 2);
+
+        manager.close_idle(Duration::from_secs(0)).unwrap();
+        assert_eq!(manager.open_logs(), 0);
+    }
+
+    #[test]
+    fn disk_budget_is_enforced_across_topics() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        // budget of 50 1K records
+        let mut manager = LogManager::new(dir, config(), 50 * 1024, 10).unwrap();
+        let payload = vec![1u8; 1024];
+        for _ in 0..100 {
+            manager.append("big", &payload).unwrap();
+        }
+
+        for _ in 0..10 {
+            manager.append("small", &payload).unwrap();
+        }
+
+        assert!(manager.size() <= 50 * 1024 + 2 * 100 * 16);
+        assert!(manager.read("big", 0, 0).is_err());
+        assert_eq!(manager.read("small", 0, 0).unwrap(), payload);
+
+        manager.remove("big").unwrap();
+        assert_eq!(manager.topics(), vec!["small".to_owned()]);
+    }
+
+    #[test]
+    fn invalid_topic_names_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = LogManager::new(dir.path(), config(), 1024 * 1024, 10).unwrap();
+
+        assert!(manager.append("../a", b"hello").is_err());
+        assert!(manager.append("", b"hello").is_err());
+        assert!(manager.topics().is_empty());
+    }
+
+    #[test]
+    fn topics_archive_to_stores_of_their_own() {
+        use crate::disk::{ArchiveStore, DirArchive};
+        use std::sync::Arc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let archive_dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segments: 2,
+            archive: Some(Arc::new(DirArchive::new(archive_dir.path()).unwrap())),
+            ..config()
+        };
+
+        let mut manager =
+            LogManager::new(dir.path(), config.clone(), 10 * 1024 * 1024, 10).unwrap();
+        for _ in 0..500 {
+            manager.append("a", &[1; 100]).unwrap();
+            manager.append("b", &[2; 100]).unwrap();
+        }
+
+        manager.close_all().unwrap();
+        let a = DirArchive::new(archive_dir.path().join("a"))
+            .unwrap()
+            .list()
+            .unwrap();
+        let b = DirArchive::new(archive_dir.path().join("b"))
+            .unwrap()
+            .list()
+            .unwrap();
+        assert!(!a.is_empty());
+        assert_eq!(a, b);
+
+        // archived chunks of both topics are found again after a restart
+        let mut manager = LogManager::new(dir.path(), config, 10 * 1024 * 1024, 10).unwrap();
+        assert_eq!(manager.read("a", a[0], 0).unwrap(), vec![1; 100]);
+        assert_eq!(manager.read("b", b[0], 0).unwrap(), vec![2; 100]);
+    }
+}
@@ -1,17 +1,163 @@
+pub mod archive;
+mod chunk;
+pub mod compression;
+#[cfg(target_os = "linux")]
+mod direct;
+pub mod encryption;
+mod export;
+mod header;
 pub mod index;
+pub mod manager;
+pub mod partition;
+mod scan;
 pub mod segment;
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+mod uring;
+mod worker;
+
+pub use archive::{ArchiveStore, DirArchive};
+pub use compression::Compression;
+pub use encryption::Encryption;
+pub use index::{IndexEncoding, IndexInterval};
+pub use manager::LogManager;
+pub use partition::PartitionedLog;
+
+use chunk::Chunk;
+use segment::SegmentOptions;
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+use uring::Ring;
+use worker::{Build, Worker};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsFd;
+
+/// File which holds the start offset of the log set by `delete_records_before`
+pub(crate) const START_OFFSET_FILE: &str = "log-start-offset";
+
+/// Sizing and retention configuration of a `DiskLog`
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Maximum size of the index of a chunk
+    pub max_index_size: u64,
+    /// Size after which active segment is rolled over to a new chunk
+    pub max_segment_size: u64,
+    /// Maximum number of chunks in the log directory. Oldest chunk is deleted,
+    /// or archived when there's an `archive`, when this is crossed
+    pub max_segments: usize,
+    /// Maximum size of a record. Records bigger than `max_segment_size` are
+    /// written to a segment of their own
+    pub max_record_size: u64,
+    /// Codec to compress new records with
+    pub compression: Compression,
+    /// Encrypts new records and decrypts encrypted records when set
+    pub encryption: Option<Encryption>,
+    /// How often records are indexed. Only applies to new chunks
+    pub index_interval: IndexInterval,
+    /// Layout of entries of new indexes. `Compact` needs segments and records
+    /// which fit in its 32 bit fields
+    pub index_encoding: IndexEncoding,
+    /// Opens the next chunk ahead of time, closes sealed chunks and deletes or
+    /// archives removed chunks on a background thread. Removed files might stay
+    /// on disk for a while
+    pub background: bool,
+    /// Allocates `max_segment_size` bytes of disk space for active segments
+    /// upfront with `fallocate`. Reduces fragmentation and filesystem metadata
+    /// updates of appends. Linux only
+    pub preallocate: bool,
+    /// Reads and writes segments with `O_DIRECT` which bypasses the page cache.
+    /// Keeps memory use predictable at the cost of throughput. Linux only. Falls
+    /// back to buffered I/O on filesystems which don't support it
+    pub direct_io: bool,
+    /// Maximum number of sealed chunks kept open. Sealed chunks are opened
+    /// read-only when they are read and least recently read chunks are closed
+    pub max_open_chunks: usize,
+    /// Opens the log without modifying anything on disk. Writes fail. Records
+    /// which aren't completely written by the writer of the log are left out
+    pub read_only: bool,
+    /// Secondary storage which old chunks are moved to instead of deleting them.
+    /// Archived chunks are still readable
+    pub archive: Option<Arc<dyn ArchiveStore>>,
+    /// Maximum number of archived chunks. Oldest archived chunk is deleted when
+    /// this is crossed
+    pub max_archived_segments: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_index_size: 10 * 1024 * 1024,
+            max_segment_size: 100 * 1024 * 1024,
+            max_segments: 10,
+            max_record_size: 10 * 1024,
+            compression: Compression::None,
+            encryption: None,
+            index_interval: IndexInterval::Record,
+            index_encoding: IndexEncoding::Wide,
+            background: false,
+            preallocate: false,
+            direct_io: false,
+            max_open_chunks: 8,
+            read_only: false,
+            archive: None,
+            max_archived_segments: usize::MAX,
+        }
+    }
+}
+
+impl Config {
+    /// Config of the log named `name` inside a group of logs, e.g a topic or a
+    /// partition. Every log of the group archives to its own store
+    pub(crate) fn scope(&self, name: &str) -> io::Result<Config> {
+        let archive = match &self.archive {
+            Some(archive) => Some(archive.scope(name)?),
+            None => None,
+        };
+
+        Ok(Config {
+            archive,
+            ..self.clone()
+        })
+    }
+}
+
+/// Error of strict reads whose first record doesn't fit in the size. Comes
+/// inside an `io::Error` of kind `InvalidInput`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordTooLarge {
+    /// Size of the record. Size on disk or decoded size, whichever crosses
+    /// the size of the read
+    pub size: u64,
+}
 
-use index::Index;
-use segment::Segment;
+impl RecordTooLarge {
+    /// Finds the error in an `io::Error` of a read
+    pub fn from_io(e: &io::Error) -> Option<&RecordTooLarge> {
+        e.get_ref().and_then(|e| e.downcast_ref::<RecordTooLarge>())
+    }
+}
+
+impl fmt::Display for RecordTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Record of {} bytes too large for buffer", self.size)
+    }
+}
 
-use std::collections::HashMap;
-use std::fs;
-use std::io;
-use std::path::PathBuf;
+impl std::error::Error for RecordTooLarge {}
 
-struct Chunk {
-    index: Index,
-    segment: Segment,
+impl From<RecordTooLarge> for io::Error {
+    fn from(e: RecordTooLarge) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
 }
 
 pub struct DiskLog {
@@ -20,8 +166,36 @@ pub struct DiskLog {
     max_index_size: u64,
     base_offsets: Vec<u64>,
     max_segments: usize,
+    max_record_size: u64,
     active_chunk: u64,
+    /// Active chunk and sealed chunks which are open
     chunks: HashMap<u64, Chunk>,
+    max_open_chunks: usize,
+    /// Open sealed chunks from least to most recently used
+    lru: VecDeque<u64>,
+    /// Sealed chunks which aren't synced since they were rolled. Chunks leave
+    /// it when they are removed, so it never has more than the chunks of the log
+    unsynced: Vec<u64>,
+    read_only: bool,
+    archive: Option<Arc<dyn ArchiveStore>>,
+    max_archived_segments: usize,
+    /// Base offsets of archived chunks. These are the oldest chunks of the log
+    archived: Vec<u64>,
+    /// Chunk which the worker is moving to the archive. It's archived once the
+    /// move succeeds
+    archiving: Option<u64>,
+    compression: Compression,
+    encryption: Option<Encryption>,
+    index_interval: IndexInterval,
+    index_encoding: IndexEncoding,
+    preallocate: bool,
+    direct_io: bool,
+    /// Records before this offset are deleted even if their chunk is still around
+    start_offset: u64,
+    worker: Option<Worker>,
+    /// Submits segment writes, reads of `readv` and syncs in batches
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    ring: Option<Ring>,
 }
 
 impl DiskLog {
@@ -31,102 +205,496 @@ impl DiskLog {
         max_segment_size: u64,
         max_segments: usize,
     ) -> io::Result<DiskLog> {
+        let config = Config {
+            max_index_size,
+            max_segment_size,
+            max_segments,
+            ..Config::default()
+        };
+
+        DiskLog::with_config(dir, config)
+    }
+
+    /// Opens an existing log for reads without modifying anything on disk. Works
+    /// on read-only filesystems and on logs which are still being written
+    pub fn open_read_only<P: Into<PathBuf>>(dir: P) -> io::Result<DiskLog> {
+        let config = Config {
+            read_only: true,
+            ..Config::default()
+        };
+
+        DiskLog::with_config(dir, config)
+    }
+
+    pub fn with_config<P: Into<PathBuf>>(dir: P, config: Config) -> io::Result<DiskLog> {
+        let Config {
+            max_index_size,
+            max_segment_size,
+            max_segments,
+            max_record_size,
+            compression,
+            encryption,
+            index_interval,
+            index_encoding,
+            background,
+            preallocate,
+            direct_io,
+            max_open_chunks,
+            read_only,
+            archive,
+            max_archived_segments,
+        } = config;
+
         let dir = dir.into();
-        let _ = fs::create_dir_all(&dir);
+        if !read_only {
+            let _ = fs::create_dir_all(&dir);
+        }
+
         if max_segment_size < 1024 || max_index_size < 100 {
             panic!("size should be at least 1KB")
         }
 
-        let files = fs::read_dir(&dir)?;
-        let mut base_offsets = Vec::new();
-        for file in files {
-            let path = file?.path();
-            let offset = path.file_stem().unwrap().to_str().unwrap();
-            let offset = offset.parse::<u64>().unwrap();
-            base_offsets.push(offset);
+        // records are appended till the segment crosses its maximum size
+        if max_segment_size > index_encoding.max_position()
+            || max_record_size > index_encoding.max_len()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Segment or record size too big for index encoding",
+            ));
         }
 
-        base_offsets.sort();
-        let mut chunks = HashMap::new();
+        // index and segment files of a chunk have the same base offset
+        let mut base_offsets = scan::scan(&dir, max_index_size, index_encoding, read_only)?;
+        let mut archived = match &archive {
+            Some(archive) => archive.list()?,
+            None => Vec::new(),
+        };
+
+        archived.sort_unstable();
+        if base_offsets.is_empty() && !archived.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Log has archived chunks but no active chunk",
+            ));
+        }
 
-        let active_segment = if let Some((last_offset, offsets)) = base_offsets.split_last() {
-            // Initialized filled segments
-            for base_offset in offsets.iter() {
-                let index = Index::new(&dir, *base_offset, max_index_size, false)?;
-                let segment = Segment::new(&dir, *base_offset)?;
-                let chunk = Chunk { index, segment };
-                chunks.insert(*base_offset, chunk);
+        if base_offsets.is_empty() {
+            if read_only {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "No chunks in log directory",
+                ));
             }
 
-            // Initialize active segment
-            let index = Index::new(&dir, *last_offset, max_index_size, true)?;
-            let segment = Segment::new(&dir, *last_offset)?;
-            let mut chunk = Chunk { index, segment };
+            base_offsets.push(0);
+        }
+
+        // archived chunks which are still in the log directory weren't removed from
+        // the directory before a crash. the directory wins
+        archived.retain(|base_offset| *base_offset < base_offsets[0]);
+        base_offsets.splice(0..0, archived.iter().copied());
 
-            // Wrong counts due to unclosed segments are handled during initialization. We can just assume
-            // count is always right from here on
-            let next_offset = chunk.index.count();
-            chunk.segment.set_next_offset(next_offset);
-            chunks.insert(*last_offset, chunk);
-            *last_offset
+        let start_offset = read_start_offset(&dir)?.unwrap_or(0);
+        let worker = if background && !read_only {
+            Some(Worker::new(&dir)?)
         } else {
-            let index = Index::new(&dir, 0, max_index_size, true)?;
-            let segment = Segment::new(&dir, 0)?;
-            let chunk = Chunk { index, segment };
-            chunks.insert(0, chunk);
-            base_offsets.push(0);
-            0
+            None
         };
 
-        let log = DiskLog {
+        let mut log = DiskLog {
             dir,
             max_segment_size,
             max_index_size,
             max_segments,
+            max_record_size,
             base_offsets,
-            chunks,
-            active_chunk: active_segment,
+            chunks: HashMap::new(),
+            max_open_chunks,
+            lru: VecDeque::new(),
+            unsynced: Vec::new(),
+            read_only,
+            archive,
+            max_archived_segments,
+            archived,
+            archiving: None,
+            active_chunk: 0,
+            compression,
+            encryption,
+            index_interval,
+            index_encoding,
+            preallocate,
+            direct_io,
+            start_offset,
+            worker,
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            ring: Ring::new(),
         };
 
+        // Initialize the active segment. Filled segments are opened when they are read.
+        // Wrong counts due to unclosed segments are handled during initialization. We
+        // can just assume count is always right from here on
+        let last_offset = *log.base_offsets.last().unwrap();
+        let chunk = log.open_chunk(last_offset, true)?;
+        log.chunks.insert(last_offset, chunk);
+        log.active_chunk = last_offset;
+        log.stage_next()?;
         Ok(log)
     }
 
-    pub fn append(&mut self, record: &[u8]) -> io::Result<()> {
-        let active_chunk = if let Some(v) = self.chunks.get_mut(&self.active_chunk) {
+    /// Base offset of the oldest chunk
+    pub fn head(&self) -> u64 {
+        self.base_offsets[0]
+    }
+
+    /// Offset of the first record which can be read
+    pub fn start_offset(&self) -> u64 {
+        self.start_offset.max(self.head())
+    }
+
+    /// Offset the next appended record gets
+    pub fn next_offset(&self) -> u64 {
+        self.active_chunk + self.chunks[&self.active_chunk].count()
+    }
+
+    /// Number of chunks in the log including archived chunks
+    pub fn segment_count(&self) -> usize {
+        self.base_offsets.len()
+    }
+
+    /// Total bytes used by segments and indexes of all the chunks. Chunks which
+    /// aren't open are counted by the size of their files. Archived chunks aren't
+    /// counted
+    pub fn size(&self) -> u64 {
+        let mut size = 0;
+        for base_offset in self.base_offsets[self.archived.len()..].iter() {
+            if let Some(chunk) = self.chunks.get(base_offset) {
+                size += chunk.segment.size() + chunk.index.size;
+                continue;
+            }
+
+            for extension in ["index", "segment"] {
+                let file = self.dir.join(format!("{:020}.{}", base_offset, extension));
+                size += fs::metadata(file).map_or(0, |metadata| metadata.len());
+            }
+        }
+
+        size
+    }
+
+    /// Appends record to the active segment and returns base offset of the
+    /// segment along with relative offset of the record
+    pub fn append(&mut self, record: &[u8]) -> io::Result<(u64, u64)> {
+        self.check_writable()?;
+        let record_size = record.len() as u64;
+        if record_size > self.max_record_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Max record size exceeded",
+            ));
+        }
+
+        let active_chunk = if let Some(v) = self.chunks.get(&self.active_chunk) {
             v
         } else {
-            return Err(io::Error::new(io::ErrorKind::Other, "No active segment"));
+            return Err(io::Error::other("No active segment"));
+        };
+
+        // Records which don't fit in a segment get a dedicated segment. Next append
+        // rolls over again as this segment is already full
+        let oversize = record_size > self.max_segment_size && active_chunk.count() > 0;
+        let segment_full = active_chunk.segment.size() >= self.max_segment_size;
+        if segment_full || active_chunk.index.is_full() || oversize {
+            self.roll()?;
+        }
+
+        // write record to segment and index
+        let offset = self.chunks[&self.active_chunk].count();
+        let (flags, record) = self.encode(self.active_chunk, offset, record)?;
+        let active_chunk = self.chunks.get_mut(&self.active_chunk).unwrap();
+        let offset = active_chunk.append(&record, flags)?;
+        self.stage_next()?;
+        Ok((self.active_chunk, offset))
+    }
+
+    /// Starts opening the next active chunk on the worker once the active chunk
+    /// is full so that the roll doesn't have to create it
+    fn stage_next(&mut self) -> io::Result<()> {
+        let active_chunk = &self.chunks[&self.active_chunk];
+        let segment_full = active_chunk.segment.size() >= self.max_segment_size;
+        if self.worker.is_none() || !(segment_full || active_chunk.index.is_full()) {
+            return Ok(());
+        }
+
+        let base_offset = self.next_offset();
+        let build = self.build_chunk(base_offset);
+        self.worker.as_mut().unwrap().stage(base_offset, build)
+    }
+
+    /// Closes the active chunk and creates a new active chunk after it. Deletes
+    /// or archives the oldest chunks while there are more than `max_segments`
+    /// chunks in the log directory
+    fn roll(&mut self) -> io::Result<()> {
+        // chunks sealed by earlier rolls which failed to close lost their records.
+        // chunk which failed to move to the archive is archived again below
+        self.finish_archiving()?;
+        if let Some(worker) = &mut self.worker {
+            worker.reap()?;
+        }
+
+        // sealed chunk is opened read-only when it's read again
+        let mut active_chunk = self.chunks.remove(&self.active_chunk).unwrap();
+        if !self.unsynced.contains(&self.active_chunk) {
+            self.unsynced.push(self.active_chunk);
+        }
+
+        // update active chunk. worker closes the sealed chunk and has usually
+        // opened the next one already
+        let base_offset = active_chunk.base_offset() + active_chunk.count();
+        let staged = match &mut self.worker {
+            Some(worker) => {
+                worker.close(self.active_chunk, active_chunk);
+                worker.take_staged(base_offset)?
+            }
+            None => {
+                active_chunk.close()?;
+                None
+            }
         };
 
-        if active_chunk.segment.size() >= self.max_segment_size {
-            active_chunk.segment.close()?;
-            active_chunk.index.close()?;
+        let chunk = match staged {
+            Some(chunk) => chunk,
+            None => self.open_chunk(base_offset, true)?,
+        };
+
+        self.chunks.insert(base_offset, chunk);
+        self.base_offsets.push(base_offset);
+        self.active_chunk = base_offset;
+
+        // chunks which failed to move to the archive catch up
+        while self.local_chunks() > self.max_segments {
+            self.finish_archiving()?;
+            let oldest = self.base_offsets[self.archived.len()];
+            match self.archive {
+                Some(_) => self.archive_chunk(oldest)?,
+                None => self.remove(oldest)?,
+            }
+        }
 
-            // update active chunk
-            let base_offset = active_chunk.index.base_offset() + active_chunk.index.count();
-            let index = Index::new(&self.dir, base_offset, self.max_index_size, true)?;
-            let segment = Segment::new(&self.dir, base_offset)?;
-            let chunk = Chunk { index, segment };
-            self.chunks.insert(base_offset, chunk);
-            self.base_offsets.push(base_offset);
-            self.active_chunk = base_offset;
+        Ok(())
+    }
+
+    /// Number of chunks in the log directory which aren't being archived
+    fn local_chunks(&self) -> usize {
+        self.base_offsets.len() - self.archived.len() - self.archiving.is_some() as usize
+    }
 
-            if self.base_offsets.len() > self.max_segments {
-                let remove_offset = self.base_offsets.remove(0);
-                self.remove(remove_offset)?;
+    /// Moves the sealed chunk to the archive where it's read from from now on.
+    /// Chunks are moved by the worker when there is one and are archived once
+    /// `finish_archiving` sees the move succeed
+    fn archive_chunk(&mut self, base_offset: u64) -> io::Result<()> {
+        let archive = self.archive.clone().unwrap();
+        self.lru.retain(|offset| *offset != base_offset);
+        let chunk = self.chunks.remove(&base_offset);
+        match &mut self.worker {
+            Some(worker) => {
+                worker.archive(archive, base_offset, chunk);
+                self.archiving = Some(base_offset);
+                Ok(())
+            }
+            None => {
+                drop(chunk);
+                archive.archive(&self.dir, base_offset)?;
+                self.add_archived(base_offset)
             }
         }
+    }
+
+    /// Waits for the chunk which the worker is moving to the archive. A chunk
+    /// which fails to move stays in the log directory and is archived again by
+    /// the next roll
+    fn finish_archiving(&mut self) -> io::Result<()> {
+        let base_offset = match self.archiving.take() {
+            Some(base_offset) => base_offset,
+            None => return Ok(()),
+        };
+
+        self.worker.as_mut().unwrap().wait(base_offset)?;
+        self.add_archived(base_offset)
+    }
+
+    /// Reads the chunk from the archive from now on. Deletes the oldest archived
+    /// chunk when there are more than `max_archived_segments` of them
+    fn add_archived(&mut self, base_offset: u64) -> io::Result<()> {
+        self.archived.push(base_offset);
+        while self.archived.len() > self.max_archived_segments {
+            let oldest = self.archived[0];
+            self.remove(oldest)?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits till the worker is done closing or archiving the chunk
+    fn wait(&mut self, base_offset: u64) -> io::Result<()> {
+        if self.archiving == Some(base_offset) {
+            return self.finish_archiving();
+        }
+
+        match &mut self.worker {
+            Some(worker) => worker.wait(base_offset),
+            None => Ok(()),
+        }
+    }
+
+    /// Directory with the files of the chunk
+    fn chunk_dir(&self, base_offset: u64) -> io::Result<PathBuf> {
+        match &self.archive {
+            Some(archive) if self.archived.binary_search(&base_offset).is_ok() => {
+                archive.dir(base_offset)
+            }
+            _ => Ok(self.dir.clone()),
+        }
+    }
+
+    /// Opens chunk with the given base offset as configured. Active chunks are
+    /// ready for appends
+    fn open_chunk(&self, base_offset: u64, active: bool) -> io::Result<Chunk> {
+        let options = self.segment_options(active);
+        let dir = self.chunk_dir(base_offset)?;
+        if self.read_only {
+            return Chunk::open_read_only(&dir, base_offset, options);
+        }
+
+        Chunk::new(
+            &dir,
+            base_offset,
+            self.max_index_size,
+            active,
+            self.index_interval,
+            self.index_encoding,
+            options,
+        )
+    }
+
+    /// Opens the active chunk with the given base offset on the worker
+    fn build_chunk(&self, base_offset: u64) -> Build {
+        let dir = self.dir.clone();
+        let options = self.segment_options(true);
+        let (max_index_size, interval, encoding) = (
+            self.max_index_size,
+            self.index_interval,
+            self.index_encoding,
+        );
+
+        Box::new(move || {
+            Chunk::new(
+                &dir,
+                base_offset,
+                max_index_size,
+                true,
+                interval,
+                encoding,
+                options,
+            )
+        })
+    }
+
+    fn segment_options(&self, active: bool) -> SegmentOptions {
+        SegmentOptions {
+            preallocate: Some(self.max_segment_size).filter(|_| self.preallocate),
+            direct: self.direct_io,
+            read_only: !active,
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            ring: self.ring.clone(),
+        }
+    }
+
+    /// Opens sealed chunks with the base offsets which aren't open yet. Least
+    /// recently used sealed chunks which aren't asked for are closed so that at
+    /// most `max_open_chunks` sealed chunks are open. Unknown base offsets are
+    /// ignored
+    fn open_chunks(&mut self, base_offsets: &[u64]) -> io::Result<()> {
+        for &base_offset in base_offsets {
+            let sealed = self.base_offsets.binary_search(&base_offset).is_ok();
+            if base_offset == self.active_chunk || !sealed {
+                continue;
+            }
+
+            match self.lru.iter().position(|offset| *offset == base_offset) {
+                Some(i) => {
+                    self.lru.remove(i);
+                }
+                None => {
+                    self.wait(base_offset)?;
+                    let chunk = self.open_chunk(base_offset, false)?;
+                    self.chunks.insert(base_offset, chunk);
+                }
+            }
+
+            self.lru.push_back(base_offset);
+        }
+
+        let mut i = 0;
+        while self.lru.len() > self.max_open_chunks && i < self.lru.len() {
+            if base_offsets.contains(&self.lru[i]) {
+                i += 1;
+                continue;
+            }
+
+            let base_offset = self.lru.remove(i).unwrap();
+            self.chunks.remove(&base_offset);
+        }
 
-        // write record to segment and index
-        let active_chunk = self.chunks.get_mut(&self.active_chunk).unwrap();
-        let (_, position) = active_chunk.segment.append(record)?;
-        active_chunk.index.write(position, record.len() as u64)?;
         Ok(())
     }
 
+    /// Compresses and encrypts the record as configured. Returns flags of the
+    /// record along with what should be written to the segment
+    fn encode<'a>(
+        &self,
+        base_offset: u64,
+        offset: u64,
+        record: &'a [u8],
+    ) -> io::Result<(u8, Cow<'a, [u8]>)> {
+        let (mut flags, record) = match self.compression.compress(record)? {
+            Some((codec, compressed)) => (codec, Cow::Owned(compressed)),
+            None => (compression::NONE, Cow::Borrowed(record)),
+        };
+
+        if let Some(encryption) = &self.encryption {
+            flags |= encryption::ENCRYPTED;
+            let encrypted = encryption.encrypt(base_offset, offset, flags, &record)?;
+            return Ok((flags, Cow::Owned(encrypted)));
+        }
+
+        Ok((flags, record))
+    }
+
     /// Read a record from correct segment
     /// Returns data, next base offset and relative offset
     pub fn read(&mut self, base_offset: u64, offset: u64) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.read_into(base_offset, offset, &mut out)?;
+        Ok(out)
+    }
+
+    /// Same as `read` but appends the record to `out` so that buffers can be
+    /// reused across reads. `out` is left as is on errors
+    pub fn read_into(
+        &mut self,
+        base_offset: u64,
+        offset: u64,
+        out: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        if base_offset + offset < self.start_offset() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Record is before start of the log",
+            ));
+        }
+
+        self.open_chunks(&[base_offset])?;
         let chunk = match self.chunks.get_mut(&base_offset) {
             Some(segment) => segment,
             None => {
@@ -137,10 +705,69 @@ impl DiskLog {
             }
         };
 
-        let (position, len) = chunk.index.read(offset)?;
+        let (position, len, flags) = chunk.entry(offset)?;
+        if is_plain(self.encryption.as_ref(), flags) {
+            let start = out.len();
+            out.resize(start + len as usize, 0);
+            if let Err(e) = chunk.segment.read(position, &mut out[start..]) {
+                out.truncate(start);
+                return Err(e);
+            }
+
+            return Ok(());
+        }
+
         let mut payload = vec![0; len as usize];
         chunk.segment.read(position, &mut payload)?;
-        Ok(payload)
+        let record = decode(
+            self.encryption.as_ref(),
+            self.max_record_size,
+            base_offset,
+            offset,
+            flags,
+            payload,
+        )?;
+
+        out.extend_from_slice(&record);
+        Ok(())
+    }
+
+    /// Reads the record at given base offset and relative offset and returns it along
+    /// with base offset and relative offset of the next record. Moves to the next segment
+    /// when relative offset crosses the boundary of a filled segment. Returns `None`
+    /// when there are no more records to read
+    pub fn read_next(
+        &mut self,
+        base_offset: u64,
+        relative_offset: u64,
+    ) -> io::Result<Option<(Vec<u8>, u64, u64)>> {
+        let (mut base_offset, mut relative_offset) = self.locate(base_offset, relative_offset);
+
+        loop {
+            self.open_chunks(&[base_offset])?;
+            let chunk = match self.chunks.get(&base_offset) {
+                Some(c) => c,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Invalid segment",
+                    ))
+                }
+            };
+
+            if relative_offset < chunk.count() {
+                break;
+            }
+
+            base_offset = match self.next_chunk(base_offset) {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+            relative_offset = 0;
+        }
+
+        let record = self.read(base_offset, relative_offset)?;
+        Ok(Some((record, base_offset, relative_offset + 1)))
     }
 
     /// Goes through index and returns chunks which tell how to sweep segments to collect
@@ -149,7 +776,17 @@ impl DiskLog {
     /// When there is more data (in other segments) current eof should move to next segment
     /// Empty segments are possible after moving to next segment
     /// EOFs after some data is collected are not errors
-    fn indexv(&self, base_offset: u64, relative_offset: u64, size: u64) -> io::Result<Chunks> {
+    /// Stops after `max_count` records even if `size` isn't reached. `strict`
+    /// leaves out the record which crosses `size`
+    fn indexv(
+        &mut self,
+        base_offset: u64,
+        relative_offset: u64,
+        size: u64,
+        max_count: u64,
+        strict: bool,
+    ) -> io::Result<Chunks> {
+        let (base_offset, relative_offset) = self.locate(base_offset, relative_offset);
         let mut chunks = Chunks {
             base_offset,
             relative_offset,
@@ -160,7 +797,8 @@ impl DiskLog {
 
         loop {
             // Get the chunk with given base offset
-            let chunk = match self.chunks.get(&chunks.base_offset) {
+            self.open_chunks(&[chunks.base_offset])?;
+            let chunk = match self.chunks.get_mut(&chunks.base_offset) {
                 Some(c) => c,
                 None if chunks.count == 0 => {
                     return Err(io::Error::new(
@@ -180,16 +818,25 @@ impl DiskLog {
             // segment isn't truncated. Index read goes past the actual size as the size calculation of the next boot is wrong.
             // This block covers both usual EOFs during normal operations as well as EOFs due to unclosed index
             // EOF due to unclosed index is a warning though
-            if chunks.relative_offset >= chunk.index.count() {
+            if chunks.relative_offset >= chunk.count() {
                 // break if we are already at the tail segment
                 if chunks.base_offset == *self.base_offsets.last().unwrap() {
-                    chunks.relative_offset -= 1;
+                    if chunks.relative_offset > 0 {
+                        chunks.relative_offset -= 1;
+                        break;
+                    }
+
+                    // empty tail chunk, e.g after truncation. last record is before it
+                    self.last_before_tail(&mut chunks)?;
                     break;
                 }
 
-                // we use 'total offsets' to go next segment. this remains same during subsequent
-                // tail reads if there are no appends. hence the above early return
-                chunks.base_offset = chunk.index.base_offset() + chunk.index.count();
+                // next chunk doesn't have to start right after this one, e.g when chunks
+                // in between are removed. tail reads stay at the tail. hence the above break
+                chunks.base_offset = match self.next_chunk(chunks.base_offset) {
+                    Some(next) => next,
+                    None => break,
+                };
                 chunks.relative_offset = 0;
                 continue;
             }
@@ -197,15 +844,36 @@ impl DiskLog {
             // Get what to read from the segment and fill the buffer. Covers the case where the logic has just moved to next
             // segment and the segment is empty
             let read_size = size - chunks.size;
+            let read_count = max_count - chunks.count;
             let (position, payload_size, count) =
-                chunk.index.readv(chunks.relative_offset, read_size)?;
+                chunk.readv(chunks.relative_offset, read_size, read_count, strict)?;
+
+            // next record doesn't fit. last record might be in the previous chunk
+            if count == 0 {
+                let last = match chunks.chunks.last() {
+                    Some(last) => last,
+                    None => {
+                        let (_, size, _) = chunk.entry(chunks.relative_offset)?;
+                        return Err(RecordTooLarge { size }.into());
+                    }
+                };
+
+                chunks.base_offset = last.base_offset;
+                chunks.relative_offset = last.relative_offset + last.count - 1;
+                break;
+            }
+
+            chunks.chunks.push(Sweep {
+                base_offset: chunks.base_offset,
+                relative_offset: chunks.relative_offset,
+                position,
+                size: payload_size,
+                count,
+            });
             chunks.relative_offset += count;
             chunks.count += count;
             chunks.size += payload_size;
-            chunks
-                .chunks
-                .push((chunks.base_offset, position, payload_size, count));
-            if chunks.size >= size {
+            if chunks.size >= size || chunks.count >= max_count {
                 chunks.relative_offset -= 1;
                 break;
             }
@@ -214,94 +882,1010 @@ impl DiskLog {
         Ok(chunks)
     }
 
+    /// Moves `chunks` to the last record before the empty tail chunk. That's the
+    /// last record of the last sweep or the last record of the chunk before the
+    /// tail. Logs without records before the tail chunk have nothing to read
+    fn last_before_tail(&mut self, chunks: &mut Chunks) -> io::Result<()> {
+        if let Some(last) = chunks.chunks.last() {
+            chunks.base_offset = last.base_offset;
+            chunks.relative_offset = last.relative_offset + last.count - 1;
+            return Ok(());
+        }
+
+        // the previous chunk doesn't have to end right before the tail chunk
+        let tail = self.base_offsets.len() - 1;
+        let previous = self.base_offsets[tail.saturating_sub(1)];
+        self.open_chunks(&[previous])?;
+        let count = match self.chunks.get(&previous) {
+            Some(chunk) if tail > 0 => chunk.count(),
+            _ => 0,
+        };
+        if count == 0 || previous + count <= self.start_offset() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "No records to read",
+            ));
+        }
+
+        chunks.base_offset = previous;
+        chunks.relative_offset = count - 1;
+        Ok(())
+    }
+
     /// Reads multiple packets from the disk and return base offset and relative offset of the
     /// Returns base offset, relative offset of the last record along with number of messages and count
     /// Goes to next segment when relative off set crosses boundary
+    /// `size` is compared against size of the records on disk. Returned data of
+    /// compressed records is bigger than that
     pub fn readv(
         &mut self,
         base_offset: u64,
         relative_offset: u64,
         size: u64,
     ) -> io::Result<(u64, u64, u64, Vec<u8>)> {
-        let chunks = self.indexv(base_offset, relative_offset, size)?;
+        let mut out = Vec::new();
+        let (base_offset, relative_offset, count) =
+            self.readv_into(base_offset, relative_offset, size, u64::MAX, &mut out)?;
+        Ok((base_offset, relative_offset, count, out))
+    }
 
-        // Fill the pre-allocated buffer
-        let mut out = vec![0; chunks.size as usize];
-        let mut start = 0;
-        for c in chunks.chunks {
-            let chunk = match self.chunks.get_mut(&c.0) {
-                Some(c) => c,
-                None => break,
-            };
+    /// Same as `readv` but appends the records to `out` so that buffers can be
+    /// reused across reads. Reads at most `max_count` records. At least one
+    /// record is read irrespective of `size` and `max_count`. `out` is left as
+    /// is on errors
+    pub fn readv_into(
+        &mut self,
+        base_offset: u64,
+        relative_offset: u64,
+        size: u64,
+        max_count: u64,
+        out: &mut Vec<u8>,
+    ) -> io::Result<(u64, u64, u64)> {
+        let chunks = self.indexv(base_offset, relative_offset, size, max_count, false)?;
+        self.fill_or_reset(&chunks, out)
+    }
 
-            let position = c.1;
-            let payload_size = c.2;
-            chunk
-                .segment
-                .read(position, &mut out[start..start + payload_size as usize])?;
-            start += payload_size as usize;
+    /// Same as `readv_into` but never reads more than `size` bytes of records,
+    /// neither on disk nor after they are decoded. Reads fail with a
+    /// `RecordTooLarge` error when the first record alone is bigger than `size`
+    pub fn readv_strict(
+        &mut self,
+        base_offset: u64,
+        relative_offset: u64,
+        size: u64,
+        max_count: u64,
+        out: &mut Vec<u8>,
+    ) -> io::Result<(u64, u64, u64)> {
+        let chunks = self.indexv(base_offset, relative_offset, size, max_count, true)?;
+        let start = out.len();
+        let read = self.fill_or_reset(&chunks, out)?;
+        if (out.len() - start) as u64 <= size {
+            return Ok(read);
         }
 
-        Ok((
-            chunks.base_offset,
-            chunks.relative_offset,
-            chunks.count,
-            out,
-        ))
+        // decompressed records are bigger than they are on disk. read them one
+        // by one till they don't fit
+        out.truncate(start);
+        let (mut last, mut count) = ((chunks.base_offset, chunks.relative_offset), 0);
+        for sweep in &chunks.chunks {
+            for offset in sweep.relative_offset..sweep.relative_offset + sweep.count {
+                let end = out.len();
+                if let Err(e) = self.read_into(sweep.base_offset, offset, out) {
+                    out.truncate(start);
+                    return Err(e);
+                }
+
+                if (out.len() - start) as u64 > size {
+                    let record = (out.len() - end) as u64;
+                    out.truncate(end);
+                    if count == 0 {
+                        return Err(RecordTooLarge { size: record }.into());
+                    }
+
+                    return Ok((last.0, last.1, count));
+                }
+
+                last = (sweep.base_offset, offset);
+                count += 1;
+            }
+        }
+
+        Ok((last.0, last.1, count))
     }
 
-    pub fn close(&mut self, base_offset: u64) -> io::Result<()> {
-        if let Some(chunk) = self.chunks.get_mut(&base_offset) {
-            chunk.index.close()?;
-            chunk.segment.close()?;
+    /// Appends records of the sweeps to `out` and returns the position of the
+    /// last record along with the count. `out` is reset on errors
+    fn fill_or_reset(&mut self, chunks: &Chunks, out: &mut Vec<u8>) -> io::Result<(u64, u64, u64)> {
+        // chunks of earlier sweeps might be closed while indexing later sweeps
+        let base_offsets: Vec<u64> = chunks
+            .chunks
+            .iter()
+            .map(|sweep| sweep.base_offset)
+            .collect();
+        self.open_chunks(&base_offsets)?;
+
+        let start = out.len();
+        if let Err(e) = self.fill(chunks, out) {
+            out.truncate(start);
+            return Err(e);
         }
 
-        Ok(())
+        Ok((chunks.base_offset, chunks.relative_offset, chunks.count))
     }
 
-    // Removes segment with given base offset from the disk and the system
-    pub fn remove(&mut self, base_offset: u64) -> io::Result<()> {
-        if let Some(mut chunk) = self.chunks.remove(&base_offset) {
-            chunk.segment.close()?;
+    /// Appends records of the sweeps to `out`
+    fn fill(&mut self, chunks: &Chunks, out: &mut Vec<u8>) -> io::Result<()> {
+        let start = out.len();
+        out.resize(start + chunks.size as usize, 0);
+        let swept = self.read_sweeps(&chunks.chunks, &mut out[start..])?;
+
+        // Compressed and encrypted records are decoded and headers of records are stripped.
+        // Raw data from the first sweep which is decoded is moved out of `out` once and
+        // sweeps are appended back one by one so that the rest isn't moved for every sweep
+        let mut end = start;
+        let mut raw: Option<(Vec<u8>, usize)> = None;
+        for sweep in &chunks.chunks[..swept] {
+            let chunk = &self.chunks[&sweep.base_offset];
+            let encryption = self.encryption.as_ref();
+            let size = sweep.size as usize;
+            let data = match &raw {
+                Some((raw, position)) => &raw[*position..*position + size],
+                None => &out[end..end + size],
+            };
 
-            let file: PathBuf = self.dir.clone();
-            let index_file_name = format!("{:020}.index", base_offset);
-            let segment_file_name = format!("{:020}.segment", base_offset);
+            let records = decode_sweep(encryption, self.max_record_size, chunk, sweep, data)?;
+            match (&mut raw, records) {
+                (None, None) => end += size,
+                (None, Some(records)) => {
+                    raw = Some((out.split_off(end), size));
+                    out.extend_from_slice(&records);
+                }
+                (Some((raw, position)), records) => {
+                    let data = &raw[*position..*position + size];
+                    out.extend_from_slice(records.as_deref().unwrap_or(data));
+                    *position += size;
+                }
+            }
+        }
 
-            // dbg!(file.join(&index_file_name));
-            fs::remove_file(file.join(index_file_name))?;
-            fs::remove_file(file.join(segment_file_name))?;
+        if let Some((raw, position)) = raw {
+            out.extend_from_slice(&raw[position..]);
         }
 
         Ok(())
     }
 
-    pub fn close_all(&mut self) -> io::Result<()> {
-        for (_, chunk) in self.chunks.iter_mut() {
-            chunk.index.close()?;
-            chunk.segment.close()?;
+    /// Reads raw data of the sweeps one after the other into `out`. Returns
+    /// number of sweeps read. Reads of all the segments are submitted together
+    /// when there is an io_uring
+    fn read_sweeps(&mut self, sweeps: &[Sweep], out: &mut [u8]) -> io::Result<usize> {
+        #[cfg(all(target_os = "linux", feature = "io_uring"))]
+        let mut reads = Vec::new();
+
+        let mut rest = out;
+        let mut swept = 0;
+        for sweep in sweeps {
+            let chunk = match self.chunks.get_mut(&sweep.base_offset) {
+                Some(c) => c,
+                None => break,
+            };
+
+            let (buf, tail) = rest.split_at_mut(sweep.size as usize);
+            rest = tail;
+            swept += 1;
+
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            if self.ring.is_some() {
+                if let Some((fd, position)) = chunk.segment.read_target(sweep.position)? {
+                    reads.push(uring::Read { fd, position, buf });
+                    continue;
+                }
+            }
+
+            chunk.segment.read(sweep.position, buf)?;
         }
 
-        Ok(())
+        #[cfg(all(target_os = "linux", feature = "io_uring"))]
+        if let Some(ring) = &self.ring {
+            ring.read_all(&mut reads)?;
+        }
+
+        Ok(swept)
     }
 
-    pub fn remove_all(&mut self) -> io::Result<()> {
-        self.close_all()?;
-        fs::remove_dir(&self.dir)?;
+    /// Syncs indexes and segments of the open chunks and chunks which are rolled
+    /// since the last sync to the disk. Segments are synced in one batch when
+    /// there is an io_uring
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.finish_archiving()?;
+        if let Some(worker) = &mut self.worker {
+            worker.sync()?;
+        }
 
-        Ok(())
-    }
-}
+        let unsynced = std::mem::take(&mut self.unsynced);
+        self.open_chunks(&unsynced)?;
 
-/// Captured state while sweeping indexes collect a bulk of records
-/// from segment/segments
-/// TODO: 'chunks' vector arguments aren't readable
+        #[cfg(all(target_os = "linux", feature = "io_uring"))]
+        if let Some(ring) = &self.ring {
+            let mut fds = Vec::new();
+            for chunk in self.chunks.values_mut() {
+                chunk.index.sync()?;
+                chunk.segment.flush()?;
+                fds.push(chunk.segment.fd());
+            }
+
+            return ring.sync_all(&fds);
+        }
+
+        for chunk in self.chunks.values_mut() {
+            chunk.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Same as `readv` but writes the records to the file descriptor instead of
+    /// returning them. Records which are saved as is go straight from segment
+    /// files to the descriptor with `sendfile`. Rest of the records are read and
+    /// decoded first. Returns base offset and relative offset of the last record
+    /// along with number of records and bytes written. Descriptor should be in
+    /// blocking mode
+    #[cfg(target_os = "linux")]
+    pub fn sendv(
+        &mut self,
+        base_offset: u64,
+        relative_offset: u64,
+        size: u64,
+        out: impl AsFd,
+    ) -> io::Result<(u64, u64, u64, u64)> {
+        let fd = out.as_fd();
+        let chunks = self.indexv(base_offset, relative_offset, size, u64::MAX, false)?;
+        let base_offsets: Vec<u64> = chunks
+            .chunks
+            .iter()
+            .map(|sweep| sweep.base_offset)
+            .collect();
+        self.open_chunks(&base_offsets)?;
+
+        // decoded records are written through a duplicate of the descriptor
+        let mut out: Option<File> = None;
+        let mut sent = 0;
+        for sweep in &chunks.chunks {
+            let chunk = match self.chunks.get_mut(&sweep.base_offset) {
+                Some(c) => c,
+                None => break,
+            };
+
+            if is_raw(self.encryption.as_ref(), chunk, sweep)? {
+                chunk.segment.send(sweep.position, sweep.size, fd)?;
+                sent += sweep.size;
+                continue;
+            }
+
+            let mut data = vec![0; sweep.size as usize];
+            chunk.segment.read(sweep.position, &mut data)?;
+            let encryption = self.encryption.as_ref();
+            let records = decode_sweep(encryption, self.max_record_size, chunk, sweep, &data)?
+                .unwrap_or(data);
+
+            if out.is_none() {
+                out = Some(File::from(fd.try_clone_to_owned()?));
+            }
+
+            out.as_mut().unwrap().write_all(&records)?;
+            sent += records.len() as u64;
+        }
+
+        Ok((
+            chunks.base_offset,
+            chunks.relative_offset,
+            chunks.count,
+            sent,
+        ))
+    }
+
+    pub fn close(&mut self, base_offset: u64) -> io::Result<()> {
+        if let Some(chunk) = self.chunks.get_mut(&base_offset) {
+            chunk.close()?;
+        }
+
+        Ok(())
+    }
+
+    // Removes segment with given base offset from the disk and the system.
+    // Active segment can't be removed. Files are deleted in background when
+    // `Config::background` is set
+    pub fn remove(&mut self, base_offset: u64) -> io::Result<()> {
+        self.check_writable()?;
+        if base_offset == self.active_chunk {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't remove active segment",
+            ));
+        }
+
+        if self.base_offsets.contains(&base_offset) {
+            if self.archiving == Some(base_offset) {
+                self.finish_archiving()?;
+            }
+
+            self.base_offsets.retain(|offset| *offset != base_offset);
+            self.lru.retain(|offset| *offset != base_offset);
+            self.unsynced.retain(|offset| *offset != base_offset);
+            let mut chunk = self.chunks.remove(&base_offset);
+            if let Some(chunk) = &mut chunk {
+                chunk.segment.close()?;
+            }
+
+            if let Ok(i) = self.archived.binary_search(&base_offset) {
+                self.archived.remove(i);
+                drop(chunk);
+                return self.archive.as_ref().unwrap().remove(base_offset);
+            }
+
+            if let Some(worker) = &self.worker {
+                return worker.delete(base_offset, chunk);
+            }
+
+            drop(chunk);
+
+            let file: PathBuf = self.dir.clone();
+            let index_file_name = format!("{:020}.index", base_offset);
+            let segment_file_name = format!("{:020}.segment", base_offset);
+
+            // dbg!(file.join(&index_file_name));
+            fs::remove_file(file.join(index_file_name))?;
+            fs::remove_file(file.join(segment_file_name))?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops all the records at and after the offset. Chunks after the chunk of
+    /// the offset are deleted and the chunk with the offset is truncated and
+    /// becomes the active chunk. Next append gets this offset
+    pub fn truncate(&mut self, offset: u64) -> io::Result<()> {
+        self.check_writable()?;
+        if offset < self.start_offset() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't truncate before start of the log",
+            ));
+        }
+
+        self.finish_archiving()?;
+        let base_offset = self.chunk_of(offset);
+        if self.archived.binary_search(&base_offset).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't truncate archived chunks",
+            ));
+        }
+
+        let relative_offset = offset - base_offset;
+        let active = base_offset == self.active_chunk;
+        if active && relative_offset >= self.chunks[&base_offset].count() {
+            return Ok(());
+        }
+
+        // chunk which is staged after the active chunk doesn't continue the log
+        if let Some(worker) = &mut self.worker {
+            worker.discard_staged()?;
+            worker.wait(base_offset)?;
+        }
+
+        // delete newer chunks first so that a crash in between doesn't leave gaps
+        self.active_chunk = base_offset;
+        let later: Vec<u64> = self
+            .base_offsets
+            .iter()
+            .rev()
+            .take_while(|offset| **offset > base_offset)
+            .copied()
+            .collect();
+
+        for offset in later {
+            self.remove(offset)?;
+        }
+
+        // reopen the chunk to make sure that it's ready for appends. sealed chunks
+        // are read-only till then and their files might be shared with snapshots
+        self.lru.retain(|offset| *offset != base_offset);
+        if !active {
+            for extension in ["index", "segment"] {
+                unshare(&self.dir.join(format!("{:020}.{}", base_offset, extension)))?;
+            }
+        }
+
+        let mut chunk = match self.chunks.remove(&base_offset) {
+            Some(chunk) if active => chunk,
+            _ => self.open_chunk(base_offset, true)?,
+        };
+
+        chunk.truncate(relative_offset)?;
+        chunk.close()?;
+        drop(chunk);
+
+        let chunk = self.open_chunk(base_offset, true)?;
+        self.chunks.insert(base_offset, chunk);
+        self.stage_next()
+    }
+
+    /// Moves start of the log to the offset. Chunks which only have records before
+    /// the offset are deleted and rest of the records before the offset can't be
+    /// read anymore. Start offset is persisted across restarts
+    pub fn delete_records_before(&mut self, offset: u64) -> io::Result<()> {
+        self.check_writable()?;
+        if offset <= self.start_offset() {
+            return Ok(());
+        }
+
+        if offset > self.next_offset() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't delete records after the end of the log",
+            ));
+        }
+
+        // persist first. a crash before chunks are deleted only leaves hidden records
+        write_start_offset(&self.dir, offset)?;
+        self.start_offset = offset;
+
+        // active chunk is kept even if all its records are deleted
+        while self.base_offsets.len() > 1 {
+            // records of the head chunk end where the next chunk starts
+            let head = self.base_offsets[0];
+            if self.base_offsets[1] > offset {
+                break;
+            }
+
+            self.remove(head)?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the log to an empty directory which can be opened as a log. Files
+    /// of sealed chunks are hard linked, or copied when the directory is on
+    /// another filesystem, and the active chunk is copied up to its last record.
+    /// Archived chunks are only linked. Snapshots on another filesystem than
+    /// the archive start after the archived chunks. Returns next offset of the
+    /// copy. Read-only logs can't be copied as the active chunk might still be
+    /// written
+    pub fn snapshot<P: AsRef<Path>>(&mut self, dest: P) -> io::Result<u64> {
+        self.check_writable()?;
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest)?;
+        if fs::read_dir(dest)?.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Snapshot directory isn't empty",
+            ));
+        }
+
+        // sealed chunks don't change once they are closed. truncation copies them
+        // before changing them
+        self.finish_archiving()?;
+        if let Some(worker) = &mut self.worker {
+            worker.sync()?;
+        }
+
+        // older archived chunks are left out when a chunk can't be linked so that
+        // the snapshot doesn't have gaps
+        for base_offset in self.archived.iter().rev() {
+            let dir = self.archive.as_ref().unwrap().dir(*base_offset)?;
+            if let Err(e) = link_chunk(&dir, dest, *base_offset) {
+                warn!(
+                    "Leaving archived chunks till {} out of snapshot. Error = {:?}",
+                    base_offset, e
+                );
+                break;
+            }
+        }
+
+        for base_offset in self.base_offsets[self.archived.len()..].iter() {
+            if *base_offset == self.active_chunk {
+                continue;
+            }
+
+            for extension in ["index", "segment"] {
+                let file_name = format!("{:020}.{}", base_offset, extension);
+                let source = self.dir.join(&file_name);
+                if fs::hard_link(&source, dest.join(&file_name)).is_err() {
+                    fs::copy(&source, dest.join(&file_name))?;
+                    File::open(dest.join(&file_name))?.sync_all()?;
+                }
+            }
+        }
+
+        let chunk = self.chunks.get_mut(&self.active_chunk).unwrap();
+        chunk.segment.flush()?;
+        let sizes = [
+            ("index", chunk.index.file_size()),
+            ("segment", chunk.segment.file_size()),
+        ];
+
+        for (extension, size) in sizes {
+            let file_name = format!("{:020}.{}", self.active_chunk, extension);
+            let source = File::open(self.dir.join(&file_name))?;
+            let mut source = io::Read::take(source, size);
+            let mut copy = File::create(dest.join(&file_name))?;
+            io::copy(&mut source, &mut copy)?;
+            copy.sync_all()?;
+        }
+
+        if self.start_offset > 0 {
+            write_start_offset(dest, self.start_offset)?;
+        }
+
+        sync_dir(dest)?;
+        Ok(self.next_offset())
+    }
+
+    /// Writes records in the range of offsets to `writer` in a portable format
+    /// which `import` reads. Records are decompressed and decrypted, so logs with
+    /// any configuration can import them. Records which aren't in the log are
+    /// left out. Returns the number of exported records
+    ///
+    /// # Format
+    ///
+    /// Integers are big endian. Stream starts with magic `SEXP` (4 bytes) and
+    /// format version 1 (1 byte). Every record is tag 1 (1 byte), offset of the
+    /// record (8 bytes), length (4 bytes) and the record. Stream ends with tag 0
+    /// (1 byte) and number of records in the stream (8 bytes). Streams which are
+    /// cut off or have other versions are rejected by `import`
+    pub fn export<W: Write>(&mut self, range: Range<u64>, writer: W) -> io::Result<u64> {
+        let mut stream = export::Writer::new(writer)?;
+        let end = range.end.min(self.next_offset());
+        let mut offset = range.start.max(self.start_offset());
+        let mut data = Vec::new();
+        while offset < end {
+            let base_offset = self.chunk_of(offset);
+            let relative_offset = offset - base_offset;
+            let chunks = self.indexv(
+                base_offset,
+                relative_offset,
+                export::BATCH_SIZE,
+                end - offset,
+                false,
+            )?;
+
+            // chunks of earlier sweeps might be closed while indexing later sweeps
+            let base_offsets: Vec<u64> = chunks
+                .chunks
+                .iter()
+                .map(|sweep| sweep.base_offset)
+                .collect();
+            self.open_chunks(&base_offsets)?;
+
+            data.clear();
+            data.resize(chunks.size as usize, 0);
+            let swept = self.read_sweeps(&chunks.chunks, &mut data)?;
+            let mut start = 0;
+            for sweep in &chunks.chunks[..swept] {
+                let chunk = &self.chunks[&sweep.base_offset];
+                let end = start + sweep.size as usize;
+                let encryption = self.encryption.as_ref();
+                decode_records(
+                    encryption,
+                    self.max_record_size,
+                    chunk,
+                    sweep,
+                    &data[start..end],
+                    |offset, record| stream.write(sweep.base_offset + offset, record),
+                )?;
+                start = end;
+            }
+
+            offset = chunks.base_offset + chunks.relative_offset + 1;
+        }
+
+        stream.finish()
+    }
+
+    /// Appends records of a stream written by `export` and returns the number
+    /// of imported records. Records keep their offsets. An empty log moves to
+    /// the first offset of the stream, otherwise the stream should continue
+    /// from the next offset of the log. Records imported before an error stay
+    pub fn import<R: Read>(&mut self, reader: R) -> io::Result<u64> {
+        self.check_writable()?;
+        let mut stream = export::Reader::new(reader)?;
+        let mut count = 0;
+        while let Some((offset, record)) = stream.read()? {
+            let empty = self.base_offsets.len() == 1 && self.next_offset() == self.active_chunk;
+            if count == 0 && empty && offset != self.next_offset() {
+                self.rebase(offset)?;
+            }
+
+            if offset != self.next_offset() {
+                let e = format!(
+                    "Record {} doesn't continue the log at {}",
+                    offset,
+                    self.next_offset()
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+
+            self.append(&record)?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Replaces the active chunk of an empty log with a chunk at the offset.
+    /// Start offset of the log is reset
+    fn rebase(&mut self, offset: u64) -> io::Result<()> {
+        if let Some(worker) = &mut self.worker {
+            worker.discard_staged()?;
+        }
+
+        let mut chunk = self.chunks.remove(&self.active_chunk).unwrap();
+        chunk.close()?;
+        drop(chunk);
+
+        for extension in ["index", "segment"] {
+            fs::remove_file(
+                self.dir
+                    .join(format!("{:020}.{}", self.active_chunk, extension)),
+            )?;
+        }
+
+        match fs::remove_file(self.dir.join(START_OFFSET_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => self.start_offset = 0,
+        }
+
+        let chunk = self.open_chunk(offset, true)?;
+        self.chunks.insert(offset, chunk);
+        self.base_offsets = vec![offset];
+        self.active_chunk = offset;
+        Ok(())
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Log is opened read-only",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Base offset of the chunk which has the offset. Offsets before head of
+    /// the log resolve to the head
+    fn chunk_of(&self, offset: u64) -> u64 {
+        match self.base_offsets.binary_search(&offset) {
+            Ok(i) => self.base_offsets[i],
+            Err(0) => self.base_offsets[0],
+            Err(i) => self.base_offsets[i - 1],
+        }
+    }
+
+    /// Base offset of the chunk after the one at given base offset. Chunks don't
+    /// have to be contiguous. `None` at the tail of the log
+    fn next_chunk(&self, base_offset: u64) -> Option<u64> {
+        let next = match self.base_offsets.binary_search(&base_offset) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+
+        self.base_offsets.get(next).copied()
+    }
+
+    /// Resolves a cursor to the chunk which has the record. Base offset of the
+    /// cursor doesn't have to be a chunk. Cursors of deleted records move to
+    /// start of the log
+    fn locate(&self, base_offset: u64, relative_offset: u64) -> (u64, u64) {
+        let start_offset = self.start_offset();
+        let mut offset = base_offset + relative_offset;
+        if offset < start_offset {
+            warn!("Trying to read deleted records. Jumping to start of the log");
+            offset = start_offset;
+        }
+
+        let base_offset = self.chunk_of(offset);
+        (base_offset, offset - base_offset)
+    }
+
+    pub fn close_all(&mut self) -> io::Result<()> {
+        for (_, chunk) in self.chunks.iter_mut() {
+            chunk.close()?;
+        }
+
+        self.finish_archiving()?;
+        if let Some(worker) = &mut self.worker {
+            worker.discard_staged()?;
+            worker.sync()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_all(&mut self) -> io::Result<()> {
+        self.check_writable()?;
+        self.close_all()?;
+        fs::remove_dir(&self.dir)?;
+
+        Ok(())
+    }
+}
+
+fn read_start_offset(dir: &Path) -> io::Result<Option<u64>> {
+    match File::open(dir.join(START_OFFSET_FILE)) {
+        Ok(mut file) => Ok(Some(file.read_u64::<BigEndian>()?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces a file which is hard linked by snapshots with a copy so that
+/// changes to it don't show up in snapshots
+#[cfg(unix)]
+fn unshare(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    if fs::metadata(path)?.nlink() <= 1 {
+        return Ok(());
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::copy(path, &tmp)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(not(unix))]
+fn unshare(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Hard links files of the chunk into `dest`. Nothing is linked on errors
+fn link_chunk(dir: &Path, dest: &Path, base_offset: u64) -> io::Result<()> {
+    let mut linked = Vec::new();
+    for extension in ["index", "segment"] {
+        let file_name = format!("{:020}.{}", base_offset, extension);
+        if let Err(e) = fs::hard_link(dir.join(&file_name), dest.join(&file_name)) {
+            for file in linked {
+                let _ = fs::remove_file(file);
+            }
+
+            return Err(e);
+        }
+
+        linked.push(dest.join(&file_name));
+    }
+
+    Ok(())
+}
+
+/// Syncs entries of the directory, e.g files which are renamed or linked into
+/// it, to the disk
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Writes start offset to a temporary file and renames it so that a crash
+/// doesn't leave a partially written start offset behind
+fn write_start_offset(dir: &Path, offset: u64) -> io::Result<()> {
+    let tmp = dir.join(format!("{}.tmp", START_OFFSET_FILE));
+    let mut file = File::create(&tmp)?;
+    file.write_u64::<BigEndian>(offset)?;
+    file.sync_all()?;
+    fs::rename(tmp, dir.join(START_OFFSET_FILE))
+}
+
+/// Reverses `DiskLog::encode` on a record read from a segment
+fn decode(
+    encryption: Option<&Encryption>,
+    max_len: u64,
+    base_offset: u64,
+    offset: u64,
+    flags: u8,
+    record: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    // flags of records which aren't encrypted aren't authenticated. records can't opt
+    // out of encryption by clearing the flag
+    if flags & encryption::ENCRYPTED == 0 && !is_plain(encryption, 0) {
+        let e = format!(
+            "Record {} of segment {} isn't encrypted",
+            offset, base_offset
+        );
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+
+    let record = if flags & encryption::ENCRYPTED != 0 {
+        match encryption {
+            Some(encryption) => encryption.decrypt(base_offset, offset, flags, &record)?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Record is encrypted and log has no key",
+                ))
+            }
+        }
+    } else {
+        record
+    };
+
+    match flags & compression::CODEC_MASK {
+        compression::NONE => Ok(record),
+        codec => compression::decompress(codec, &record, max_len),
+    }
+}
+
+/// Whether a record with the flags is saved as is and can be returned without
+/// decoding. Records which aren't encrypted are only accepted without a key or
+/// when the key allows them
+fn is_plain(encryption: Option<&Encryption>, flags: u8) -> bool {
+    flags == 0 && encryption.is_none_or(|encryption| encryption.accepts_plaintext())
+}
+
+/// Whether records of the sweep are saved as is in the segment, i.e they
+/// don't have headers and aren't compressed or encrypted
+fn is_raw(encryption: Option<&Encryption>, chunk: &Chunk, sweep: &Sweep) -> io::Result<bool> {
+    if chunk.index.is_sparse() {
+        return Ok(false);
+    }
+
+    for offset in sweep.relative_offset..sweep.relative_offset + sweep.count {
+        let (_, _, flags) = chunk.index.read_with_flags(offset)?;
+        if !is_plain(encryption, flags) {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Decodes records of a sweep. Returns `None` when all the records in the
+/// sweep are saved as is
+fn decode_sweep(
+    encryption: Option<&Encryption>,
+    max_len: u64,
+    chunk: &Chunk,
+    sweep: &Sweep,
+    data: &[u8],
+) -> io::Result<Option<Vec<u8>>> {
+    if !chunk.index.is_sparse() && is_raw(encryption, chunk, sweep)? {
+        return Ok(None);
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    decode_records(encryption, max_len, chunk, sweep, data, |_, record| {
+        out.extend_from_slice(record);
+        Ok(())
+    })?;
+
+    Ok(Some(out))
+}
+
+/// Decodes records of a sweep one by one and hands them over to `f` along
+/// with their relative offset
+fn decode_records<F>(
+    encryption: Option<&Encryption>,
+    max_len: u64,
+    chunk: &Chunk,
+    sweep: &Sweep,
+    data: &[u8],
+    mut f: F,
+) -> io::Result<()>
+where
+    F: FnMut(u64, &[u8]) -> io::Result<()>,
+{
+    if chunk.index.is_sparse() {
+        return decode_framed_records(encryption, max_len, sweep, data, f);
+    }
+
+    let index = &chunk.index;
+    for offset in sweep.relative_offset..sweep.relative_offset + sweep.count {
+        let (position, len, flags) = index.read_with_flags(offset)?;
+        let start = position.checked_sub(sweep.position);
+        let record = slice(data, start, len).ok_or_else(|| corrupted(sweep, offset))?;
+        if is_plain(encryption, flags) {
+            f(offset, record)?;
+            continue;
+        }
+
+        let record = decode(
+            encryption,
+            max_len,
+            sweep.base_offset,
+            offset,
+            flags,
+            record.to_vec(),
+        )?;
+        f(offset, &record)?;
+    }
+
+    Ok(())
+}
+
+/// Same as `decode_records` for sweeps of sparse chunks whose records are
+/// preceded by headers
+fn decode_framed_records<F>(
+    encryption: Option<&Encryption>,
+    max_len: u64,
+    sweep: &Sweep,
+    data: &[u8],
+    mut f: F,
+) -> io::Result<()>
+where
+    F: FnMut(u64, &[u8]) -> io::Result<()>,
+{
+    let mut start = 0;
+    for offset in sweep.relative_offset..sweep.relative_offset + sweep.count {
+        let header = slice(data, Some(start), segment::HEADER_WIDTH);
+        let (len, flags) = segment::parse_header(header.ok_or_else(|| corrupted(sweep, offset))?);
+        let header_end = start + segment::HEADER_WIDTH;
+        let record = slice(data, Some(header_end), len).ok_or_else(|| corrupted(sweep, offset))?;
+        start = header_end + len;
+        if is_plain(encryption, flags) {
+            f(offset, record)?;
+            continue;
+        }
+
+        let record = decode(
+            encryption,
+            max_len,
+            sweep.base_offset,
+            offset,
+            flags,
+            record.to_vec(),
+        )?;
+        f(offset, &record)?;
+    }
+
+    Ok(())
+}
+
+/// Part of the data of a sweep. `None` when it isn't completely in the data
+fn slice(data: &[u8], start: Option<u64>, len: u64) -> Option<&[u8]> {
+    let start = start?;
+    let end = start.checked_add(len)?;
+    if end > data.len() as u64 {
+        return None;
+    }
+
+    Some(&data[start as usize..end as usize])
+}
+
+/// Error of records whose index entry or header points outside of their sweep
+fn corrupted(sweep: &Sweep, offset: u64) -> io::Error {
+    let e = format!(
+        "Record {} of segment {} is corrupted",
+        offset, sweep.base_offset
+    );
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Captured state while sweeping indexes collect a bulk of records
+/// from segment/segments
 struct Chunks {
     base_offset: u64,
     relative_offset: u64,
     count: u64,
     size: u64,
-    chunks: Vec<(u64, u64, u64, u64)>,
+    chunks: Vec<Sweep>,
+}
+
+/// Contiguous records of a segment to be read in one go
+struct Sweep {
+    base_offset: u64,
+    /// Relative offset of the first record
+    relative_offset: u64,
+    /// Position of the first record in the segment
+    position: u64,
+    size: u64,
+    count: u64,
 }
 
 #[cfg(test)]
@@ -377,7 +1961,7 @@ mod test {
 
         // 100K bytes
         let record_count = 100;
-        let record_size = 1 * 1024;
+        let record_size = 1024;
 
         // 10 records per segment. 10 segments (0.segment - 90.segment)
         let max_segment_size = 10 * 1024;
@@ -483,7 +2067,7 @@ mod test {
 
         // 15K bytes
         let record_count = 15;
-        let record_size = 1 * 1024;
+        let record_size = 1024;
 
         let max_segment_size = 10 * 1024;
         let max_index_size = record_count * 16;
@@ -537,6 +2121,1343 @@ mod test {
         assert_eq!(data.len(), 10 * 1024);
     }
 
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn compressed_records_are_transparently_decompressed() {
+        use super::{Compression, Config};
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let config = Config {
+            max_index_size: 100 * 16,
+            max_segment_size: 10 * 1024,
+            max_segments: 10,
+            compression: Compression::Lz4,
+            ..Config::default()
+        };
+
+        // Uncompressed records of a previous boot
+        let mut log = DiskLog::new(dir, 100 * 16, 10 * 1024, 10).unwrap();
+        let mut payload = vec![0u8; 1024];
+        for i in 0..5 {
+            payload[0] = i;
+            log.append(&payload).unwrap();
+        }
+
+        log.close_all().unwrap();
+
+        // 1K records compress to a few bytes. All of them end up in 0.segment
+        let mut log = DiskLog::with_config(dir, config).unwrap();
+        for i in 5..100 {
+            payload[0] = i;
+            log.append(&payload).unwrap();
+        }
+
+        assert!(log.size() < 20 * 1024);
+        let data = log.read(0, 50).unwrap();
+        assert_eq!(data.len(), 1024);
+        assert_eq!(data[0], 50);
+
+        let (base_offset, relative_offset, count, data) = log.readv(0, 0, 200 * 1024).unwrap();
+        assert_eq!((base_offset, relative_offset, count), (0, 99, 100));
+        assert_eq!(data.len(), 100 * 1024);
+        for i in 0..100 {
+            assert_eq!(data[i * 1024], i as u8);
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn batched_reads_mix_raw_and_decoded_chunks() {
+        use super::{Compression, Config};
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let plain = Config {
+            max_index_size: 100 * 16,
+            max_segment_size: 1024,
+            max_segments: 10,
+            ..Config::default()
+        };
+        let compressed = Config {
+            compression: Compression::Lz4,
+            ..plain.clone()
+        };
+
+        // 0.segment (0 - 10) is saved as is and 11.segment is compressed till it
+        // rolls. last chunk is saved as is
+        let mut log = DiskLog::with_config(dir, plain.clone()).unwrap();
+        for i in 0..11u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        log.close_all().unwrap();
+        let mut log = DiskLog::with_config(dir, compressed).unwrap();
+        let mut next = 11u8;
+        loop {
+            log.append(&[next; 100]).unwrap();
+            next += 1;
+            let chunk = &log.chunks[&11];
+            if chunk.segment.size() >= 1024 || chunk.index.is_full() {
+                break;
+            }
+        }
+
+        log.close_all().unwrap();
+        let mut log = DiskLog::with_config(dir, plain).unwrap();
+        for _ in 0..5 {
+            log.append(&[next; 100]).unwrap();
+            next += 1;
+        }
+
+        let last = *log.base_offsets.last().unwrap();
+        assert_eq!(log.base_offsets, vec![0, 11, last]);
+        let (base_offset, relative_offset, count, data) = log.readv(0, 0, 200 * 100).unwrap();
+        assert_eq!((base_offset, relative_offset), (last, 4));
+        assert_eq!(count, next as u64);
+        assert_eq!(data.len(), next as usize * 100);
+        for i in 0..next as usize {
+            assert_eq!(&data[i * 100..(i + 1) * 100], &[i as u8; 100][..]);
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypted_records_need_the_key_to_be_read() {
+        use super::{Config, Encryption};
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let config = Config {
+            max_index_size: 100 * 16,
+            max_segment_size: 10 * 1024,
+            max_segments: 10,
+            encryption: Some(Encryption::new(&[1; 32])),
+            ..Config::default()
+        };
+
+        let mut log = DiskLog::with_config(dir, config.clone()).unwrap();
+        let mut payload = vec![0u8; 1024];
+        for i in 0..25 {
+            payload[0] = i;
+            log.append(&payload).unwrap();
+        }
+
+        log.close_all().unwrap();
+
+        // segments don't contain plaintext
+        let segment = std::fs::read(dir.join(format!("{:020}.segment", 0))).unwrap();
+        assert!(!segment.windows(1024).any(|w| w[1..] == payload[1..]));
+
+        let mut log = DiskLog::with_config(dir, config).unwrap();
+        let (_, _, count, data) = log.readv(0, 0, 15 * 1024).unwrap();
+        assert_eq!(count, 15);
+        assert_eq!(data.len(), 15 * 1024);
+        for i in 0..15 {
+            assert_eq!(data[i * 1024], i as u8);
+        }
+
+        log.close_all().unwrap();
+        let mut log = DiskLog::new(dir, 100 * 16, 10 * 1024, 10).unwrap();
+        match log.read(0, 0) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => (),
+            _ => panic!("Expecting a permission denied error"),
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn flags_of_encrypted_records_are_authenticated() {
+        use super::{Config, Encryption};
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let config = Config {
+            max_index_size: 100 * 16,
+            max_segment_size: 10 * 1024,
+            max_segments: 10,
+            encryption: Some(Encryption::new(&[1; 32])),
+            ..Config::default()
+        };
+
+        let mut log = DiskLog::with_config(dir, config.clone()).unwrap();
+        log.append(&[1; 1024]).unwrap();
+        log.append(&[2; 1024]).unwrap();
+        log.close_all().unwrap();
+
+        // flags are in the first byte of the length of the second index entry
+        let path = dir.join(format!("{:020}.index", 0));
+        let mut index = std::fs::read(&path).unwrap();
+        index[16 + 16 + 8] |= 1;
+        std::fs::write(&path, index).unwrap();
+
+        let mut log = DiskLog::with_config(dir, config).unwrap();
+        assert_eq!(log.read(0, 0).unwrap(), vec![1; 1024]);
+        let e = log.read(0, 1).unwrap_err();
+        assert!(e.to_string().contains("failed authentication"));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn plaintext_records_need_to_be_allowed_when_there_is_a_key() {
+        use super::{Config, Encryption};
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        // e.g a log written before it had a key, or records with flags cleared
+        let mut log = DiskLog::new(dir, 100 * 16, 10 * 1024, 10).unwrap();
+        for i in 0..25u8 {
+            log.append(&[i; 1024]).unwrap();
+        }
+
+        log.close_all().unwrap();
+
+        let config = Config {
+            max_index_size: 100 * 16,
+            max_segment_size: 10 * 1024,
+            max_segments: 10,
+            encryption: Some(Encryption::new(&[1; 32])),
+            ..Config::default()
+        };
+
+        let mut log = DiskLog::with_config(dir, config.clone()).unwrap();
+        assert!(log.read(0, 0).is_err());
+        assert!(log.readv(0, 0, 15 * 1024).is_err());
+        assert!(log.read_next(10, 5).is_err());
+        log.close_all().unwrap();
+
+        let config = Config {
+            encryption: Some(Encryption::new(&[1; 32]).allow_plaintext()),
+            ..config
+        };
+
+        let mut log = DiskLog::with_config(dir, config).unwrap();
+        assert_eq!(log.read(0, 0).unwrap(), vec![0; 1024]);
+        let (_, _, count, data) = log.readv(0, 0, 15 * 1024).unwrap();
+        assert_eq!(count, 15);
+        assert_eq!(&data[14 * 1024..], &[14; 1024][..]);
+    }
+
+    #[test]
+    fn sparse_index_reads_work_as_expected() {
+        use super::{Config, IndexInterval};
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let config = Config {
+            max_index_size: 100 * 16,
+            max_segment_size: 10 * 1024,
+            max_segments: 10,
+            index_interval: IndexInterval::Records(4),
+            ..Config::default()
+        };
+
+        // 100 byte records. 98 records (with headers) per segment
+        let mut log = DiskLog::with_config(dir, config.clone()).unwrap();
+        for i in 0..150u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        // 0.segment (0 - 97), 98.segment (98 - 149)
+        log.close_all().unwrap();
+        let index = std::fs::metadata(dir.join(format!("{:020}.index", 0))).unwrap();
+        assert_eq!(index.len(), 16 + 25 * 16);
+
+        let mut log = DiskLog::with_config(dir, config).unwrap();
+        for i in 0..150u64 {
+            let (base_offset, offset) = if i < 98 { (0, i) } else { (98, i - 98) };
+            assert_eq!(log.read(base_offset, offset).unwrap(), vec![i as u8; 100]);
+        }
+
+        // Read 20 records across segments. Headers are stripped
+        let (base_offset, relative_offset, count, data) = log.readv(0, 90, 20 * 105).unwrap();
+        assert_eq!((base_offset, relative_offset, count), (98, 11, 20));
+        assert_eq!(data.len(), 20 * 100);
+        for i in 0..20 {
+            assert_eq!(data[i * 100], 90 + i as u8);
+        }
+
+        assert_eq!(log.append(&[150; 100]).unwrap(), (98, 52));
+    }
+
+    #[test]
+    fn full_index_rolls_over_to_new_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        // Index fits 10 entries. 100 byte records fill the index way before the segment
+        let mut log = DiskLog::new(dir, 10 * 16, 10 * 1024, 100).unwrap();
+        for i in 0..25u8 {
+            let (base_offset, offset) = log.append(&[i; 100]).unwrap();
+            assert_eq!(base_offset + offset, i as u64);
+            assert_eq!(offset, i as u64 % 10);
+        }
+
+        log.close_all().unwrap();
+        let mut log = DiskLog::new(dir, 10 * 16, 10 * 1024, 100).unwrap();
+        let (base_offset, relative_offset, count, data) = log.readv(0, 0, 100 * 1024).unwrap();
+        assert_eq!((base_offset, relative_offset, count), (20, 4, 25));
+        assert_eq!(data[24 * 100], 24);
+    }
+
+    #[test]
+    fn truncation_drops_records_after_the_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        // 0.segment (0 - 10), 11.segment (11 - 21), 22.segment (22 - 29)
+        let mut log = DiskLog::new(dir, 100 * 16, 1024, 10).unwrap();
+        for i in 0..30u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        log.truncate(40).unwrap();
+        assert_eq!(log.segment_count(), 3);
+
+        log.truncate(15).unwrap();
+        assert_eq!(log.segment_count(), 2);
+        assert!(log.read(11, 4).is_err());
+        assert!(!dir.join(format!("{:020}.segment", 22)).exists());
+        assert_eq!(log.read(11, 3).unwrap(), vec![14; 100]);
+
+        // appends continue from the truncated offset
+        assert_eq!(log.append(&[100; 100]).unwrap(), (11, 4));
+        log.close_all().unwrap();
+
+        let mut log = DiskLog::new(dir, 100 * 16, 1024, 10).unwrap();
+        assert_eq!(log.read(11, 4).unwrap(), vec![100; 100]);
+        assert!(log.read(11, 5).is_err());
+
+        // truncating at a chunk boundary leaves an empty active chunk
+        log.truncate(11).unwrap();
+        assert_eq!(log.append(&[101; 100]).unwrap(), (11, 0));
+        assert!(log.truncate(0).is_ok());
+        assert_eq!(log.append(&[102; 100]).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn deleted_records_are_hidden_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        // 0.segment (0 - 10), 11.segment (11 - 21), 22.segment (22 - 29)
+        let mut log = DiskLog::new(dir, 100 * 16, 1024, 10).unwrap();
+        for i in 0..30u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        log.delete_records_before(15).unwrap();
+        assert_eq!((log.head(), log.start_offset()), (11, 15));
+        assert!(!dir.join(format!("{:020}.segment", 0)).exists());
+        assert!(log.read(11, 3).is_err());
+        assert_eq!(log.read(11, 4).unwrap(), vec![15; 100]);
+
+        // cursors of deleted records move to start of the log
+        let (_, _, count, data) = log.readv(0, 0, 200).unwrap();
+        assert_eq!((count, data[0]), (2, 15));
+        let (record, base_offset, offset) = log.read_next(11, 0).unwrap().unwrap();
+        assert_eq!((record[0], base_offset, offset), (15, 11, 5));
+        log.close_all().unwrap();
+
+        let mut log = DiskLog::new(dir, 100 * 16, 1024, 10).unwrap();
+        assert_eq!(log.start_offset(), 15);
+        assert!(log.read(11, 3).is_err());
+        assert!(log.truncate(14).is_err());
+        assert!(log.delete_records_before(31).is_err());
+
+        // active chunk stays around even when all the records are deleted
+        log.delete_records_before(30).unwrap();
+        assert_eq!((log.head(), log.segment_count()), (22, 1));
+        assert!(log.read(22, 7).is_err());
+        assert_eq!(log.append(&[30; 100]).unwrap(), (22, 8));
+        assert_eq!(log.read_next(0, 0).unwrap().unwrap().0, vec![30; 100]);
+    }
+
+    #[test]
+    fn background_rolls_and_deletes_work_as_expected() {
+        use super::Config;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let config = Config {
+            max_index_size: 100 * 16,
+            max_segment_size: 1024,
+            max_segments: 2,
+            background: true,
+            ..Config::default()
+        };
+
+        // 11 records per chunk. last 2 chunks are retained
+        let mut log = DiskLog::with_config(dir, config.clone()).unwrap();
+        for i in 0..50u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        // removed chunks don't wait for a sync
+        assert_eq!(log.unsynced, vec![33]);
+
+        log.close_all().unwrap();
+        assert_eq!(log.head(), 33);
+        assert!(!dir.join(format!("{:020}.segment", 22)).exists());
+        assert!(!dir.join(format!("{:020}.segment.deleted", 22)).exists());
+
+        // crash before background deletion finished
+        std::fs::write(dir.join(format!("{:020}.segment.deleted", 22)), b"").unwrap();
+        drop(log);
+
+        let mut log = DiskLog::with_config(dir, config).unwrap();
+        assert!(!dir.join(format!("{:020}.segment.deleted", 22)).exists());
+        assert_eq!(log.segment_count(), 2);
+        for i in 33..50u64 {
+            let (base_offset, offset) = if i < 44 { (33, i - 33) } else { (44, i - 44) };
+            assert_eq!(log.read(base_offset, offset).unwrap(), vec![i as u8; 100]);
+        }
+
+        // next chunk is opened once the active chunk is full and used by the roll
+        for i in 50..55u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        log.worker.as_mut().unwrap().sync().unwrap();
+        assert!(dir.join(format!("{:020}.segment", 55)).exists());
+        assert_eq!(log.append(&[55; 100]).unwrap(), (55, 0));
+        assert_eq!(log.read(44, 10).unwrap(), vec![54; 100]);
+        assert_eq!(log.read(55, 0).unwrap(), vec![55; 100]);
+
+        // chunks which are staged when the log is closed are deleted
+        for i in 56..66u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        log.close_all().unwrap();
+        assert!(!dir.join(format!("{:020}.segment", 66)).exists());
+        assert!(!dir.join(format!("{:020}.index", 66)).exists());
+    }
+
+    #[test]
+    fn preallocated_logs_reopen_with_the_right_records() {
+        use super::Config;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let config = Config {
+            max_index_size: 100 * 16,
+            max_segment_size: 1024,
+            preallocate: true,
+            ..Config::default()
+        };
+
+        let mut log = DiskLog::with_config(dir, config.clone()).unwrap();
+        for i in 0..15u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        // preallocated space doesn't change length of the files
+        let segment = dir.join(format!("{:020}.segment", 0));
+        assert_eq!(std::fs::metadata(&segment).unwrap().len(), 16 + 1100);
+        log.close_all().unwrap();
+
+        let mut log = DiskLog::with_config(dir, config).unwrap();
+        assert_eq!(log.append(&[15; 100]).unwrap(), (11, 4));
+        for i in 0..16u64 {
+            let (base_offset, offset) = if i < 11 { (0, i) } else { (11, i - 11) };
+            assert_eq!(log.read(base_offset, offset).unwrap(), vec![i as u8; 100]);
+        }
+    }
+
+    #[test]
+    fn direct_io_logs_work_as_expected() {
+        use super::Config;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let config = Config {
+            max_index_size: 200 * 16,
+            max_segment_size: 10 * 1024,
+            direct_io: true,
+            ..Config::default()
+        };
+
+        // 0.segment (0 - 102), 103.segment (103 - 149)
+        let mut log = DiskLog::with_config(dir, config.clone()).unwrap();
+        for i in 0..150u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        assert_eq!(log.read(103, 2).unwrap(), vec![105; 100]);
+        log.truncate(140).unwrap();
+        log.append(&[200; 100]).unwrap();
+        log.close_all().unwrap();
+
+        // padding of the last block is trimmed
+        let segment = dir.join(format!("{:020}.segment", 103));
+        assert_eq!(std::fs::metadata(segment).unwrap().len(), 16 + 38 * 100);
+
+        let mut log = DiskLog::with_config(dir, config).unwrap();
+        let (_, _, count, data) = log.readv(0, 0, 150 * 100).unwrap();
+        assert_eq!(count, 141);
+        assert_eq!(data[139 * 100], 139);
+        assert_eq!(data[140 * 100], 200);
+    }
+
+    #[test]
+    fn padding_of_crashed_sparse_direct_io_logs_isnt_read_as_records() {
+        use super::{Config, IndexInterval};
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let config = Config {
+            max_index_size: 200 * 16,
+            max_segment_size: 10 * 1024,
+            index_interval: IndexInterval::Records(4),
+            direct_io: true,
+            ..Config::default()
+        };
+
+        // crash after the index is closed but before the segment is closed leaves
+        // the last block padded with zeros
+        let mut log = DiskLog::with_config(dir, config.clone()).unwrap();
+        for i in 0..10u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        log.sync().unwrap();
+        log.chunks.get_mut(&0).unwrap().index.close().unwrap();
+        drop(log);
+
+        let segment = dir.join(format!("{:020}.segment", 0));
+        assert_eq!(std::fs::metadata(&segment).unwrap().len(), 4096);
+
+        // readers leave the file as is
+        let read_only = Config {
+            read_only: true,
+            ..config.clone()
+        };
+        let mut log = DiskLog::with_config(dir, read_only).unwrap();
+        let (_, _, count, _) = log.readv(0, 0, 20 * 100).unwrap();
+        assert_eq!((count, log.next_offset()), (10, 10));
+        assert_eq!(std::fs::metadata(&segment).unwrap().len(), 4096);
+        drop(log);
+
+        let mut log = DiskLog::with_config(dir, config.clone()).unwrap();
+        assert_eq!(log.next_offset(), 10);
+        assert_eq!(std::fs::metadata(&segment).unwrap().len(), 16 + 10 * 105);
+        assert_eq!(log.append(&[10; 100]).unwrap(), (0, 10));
+        log.close_all().unwrap();
+
+        let mut log = DiskLog::with_config(dir, config).unwrap();
+        let (_, _, count, data) = log.readv(0, 0, 20 * 100).unwrap();
+        assert_eq!(count, 11);
+        for i in 0..11 {
+            assert_eq!(&data[i * 100..(i + 1) * 100], &[i as u8; 100][..]);
+        }
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    fn io_uring_logs_work_as_expected() {
+        use super::Config;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let config = Config {
+            max_index_size: 200 * 16,
+            max_segment_size: 10 * 1024,
+            max_segments: 100,
+            preallocate: true,
+            ..Config::default()
+        };
+
+        // 103 records per chunk. reads span all the chunks
+        let mut log = DiskLog::with_config(dir, config.clone()).unwrap();
+        for i in 0..1000u32 {
+            log.append(&[i as u8; 100]).unwrap();
+        }
+
+        log.sync().unwrap();
+        let (_, _, count, data) = log.readv(0, 0, 1000 * 100).unwrap();
+        assert_eq!(count, 1000);
+        for i in 0..1000 {
+            assert_eq!(&data[i * 100..(i + 1) * 100], &[i as u8; 100][..]);
+        }
+
+        log.truncate(500).unwrap();
+        log.append(&[1; 100]).unwrap();
+        log.close_all().unwrap();
+
+        let mut log = DiskLog::with_config(dir, config).unwrap();
+        assert_eq!(log.segment_count(), 5);
+        assert_eq!(log.read(412, 87).unwrap(), vec![243; 100]);
+        assert_eq!(log.read(412, 88).unwrap(), vec![1; 100]);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn sent_records_match_read_records() {
+        use super::{Config, IndexInterval};
+        use std::fs::File;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        // 0.segment (0 - 102), 103.segment (103 - 149)
+        let mut log = DiskLog::new(dir, 200 * 16, 10 * 1024, 10).unwrap();
+        for i in 0..150u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        log.close_all().unwrap();
+
+        // new chunks have a sparse index and records with headers
+        let config = Config {
+            max_index_size: 200 * 16,
+            max_segment_size: 10 * 1024,
+            index_interval: IndexInterval::Bytes(1000),
+            ..Config::default()
+        };
+
+        let mut log = DiskLog::with_config(dir, config).unwrap();
+        log.roll().unwrap();
+        for i in 150..250u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        let (base_offset, relative_offset, count, expected) = log.readv(0, 50, 20 * 1024).unwrap();
+
+        let path = dir.join("out");
+        let out = File::create(&path).unwrap();
+        let (base, relative, sent_count, sent) = log.sendv(0, 50, 20 * 1024, &out).unwrap();
+        assert_eq!(
+            (base, relative, sent_count),
+            (base_offset, relative_offset, count)
+        );
+        assert_eq!(sent, expected.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+        assert_eq!(&expected[..100], &[50; 100][..]);
+        assert_eq!(
+            &expected[expected.len() - 100..],
+            &[(50 + count - 1) as u8; 100][..]
+        );
+    }
+
+    #[test]
+    fn reads_into_reused_buffers_are_capped_by_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        // 0.segment (0 - 102), 103.segment (103 - 149)
+        let mut log = DiskLog::new(dir, 200 * 16, 10 * 1024, 10).unwrap();
+        for i in 0..150u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        let mut out = Vec::new();
+        log.read_into(103, 2, &mut out).unwrap();
+        assert_eq!(out, vec![105; 100]);
+        assert!(log.read_into(200, 0, &mut out).is_err());
+        assert_eq!(out.len(), 100);
+
+        // count is hit before size
+        out.clear();
+        let (base_offset, relative_offset, count) =
+            log.readv_into(0, 100, 100 * 1024, 5, &mut out).unwrap();
+        assert_eq!((base_offset, relative_offset, count), (103, 1, 5));
+        assert_eq!(out.len(), 500);
+        assert_eq!(out[200], 102);
+        assert_eq!(out[300], 103);
+
+        // records are appended to what is already in the buffer
+        let capacity = out.capacity();
+        out.clear();
+        log.readv_into(0, 0, 200, u64::MAX, &mut out).unwrap();
+        log.readv_into(0, 2, 200, u64::MAX, &mut out).unwrap();
+        assert_eq!(out.len(), 400);
+        assert_eq!(out[300], 3);
+        assert_eq!(out.capacity(), capacity);
+    }
+
+    #[test]
+    fn strict_reads_never_cross_the_size() {
+        use super::RecordTooLarge;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        // 0.segment (0 - 102), 103.segment (103 - 109)
+        let mut log = DiskLog::new(dir, 200 * 16, 10 * 1024, 10).unwrap();
+        for i in 0..110u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        let mut out = Vec::new();
+        let (base_offset, relative_offset, count) =
+            log.readv_strict(0, 0, 450, u64::MAX, &mut out).unwrap();
+        assert_eq!((base_offset, relative_offset, count), (0, 3, 4));
+        assert_eq!(out.len(), 400);
+
+        // next chunk doesn't have room for its first record
+        out.clear();
+        let (base_offset, relative_offset, count) =
+            log.readv_strict(0, 100, 350, u64::MAX, &mut out).unwrap();
+        assert_eq!((base_offset, relative_offset, count), (0, 102, 3));
+        assert_eq!(out.len(), 300);
+
+        out.clear();
+        log.append(&[200; 1000]).unwrap();
+        let e = log
+            .readv_strict(103, 7, 999, u64::MAX, &mut out)
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            RecordTooLarge::from_io(&e),
+            Some(&RecordTooLarge { size: 1000 })
+        );
+        assert!(out.is_empty());
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn strict_reads_limit_decompressed_records() {
+        use super::{Compression, Config, RecordTooLarge};
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let config = Config {
+            max_index_size: 100 * 16,
+            max_segment_size: 10 * 1024,
+            compression: Compression::Lz4,
+            ..Config::default()
+        };
+
+        // 1K records compress to a few bytes
+        let mut log = DiskLog::with_config(dir, config).unwrap();
+        for i in 0..10u8 {
+            log.append(&[i; 1024]).unwrap();
+        }
+
+        let mut out = Vec::new();
+        let (base_offset, relative_offset, count) =
+            log.readv_strict(0, 2, 3000, u64::MAX, &mut out).unwrap();
+        assert_eq!((base_offset, relative_offset, count), (0, 3, 2));
+        assert_eq!(out.len(), 2048);
+        assert_eq!(out[1024], 3);
+
+        out.clear();
+        let e = log
+            .readv_strict(0, 2, 1000, u64::MAX, &mut out)
+            .unwrap_err();
+        assert_eq!(
+            RecordTooLarge::from_io(&e),
+            Some(&RecordTooLarge { size: 1024 })
+        );
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn reads_of_deleted_chunks_jump_to_the_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        // 103 records per chunk. only 3 chunks are retained
+        let mut log = DiskLog::new(dir, 200 * 16, 10 * 1024, 3).unwrap();
+        for i in 0..400u32 {
+            log.append(&[i as u8; 100]).unwrap();
+        }
+
+        assert_eq!(log.head(), 103);
+
+        // cursor of a consumer which is behind retention
+        let (base_offset, relative_offset, count, data) = log.readv(0, 10, 1000).unwrap();
+        assert_eq!((base_offset, relative_offset, count), (103, 9, 10));
+        assert_eq!(data[0], 103);
+
+        let (record, base_offset, relative_offset) = log.read_next(0, 10).unwrap().unwrap();
+        assert_eq!((record[0], base_offset, relative_offset), (103, 103, 1));
+
+        // cursors resolve to their chunk irrespective of the base offset
+        let (base_offset, relative_offset, count, data) = log.readv(103, 150, 1000).unwrap();
+        assert_eq!((base_offset, relative_offset, count), (206, 56, 10));
+        assert_eq!(data[0], 253);
+    }
+
+    #[test]
+    fn sealed_chunks_are_opened_on_demand() {
+        use super::Config;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let config = Config {
+            max_index_size: 200 * 16,
+            max_segment_size: 10 * 1024,
+            max_segments: 100,
+            max_open_chunks: 2,
+            ..Config::default()
+        };
+
+        // 103 records per chunk
+        let mut log = DiskLog::with_config(dir, config.clone()).unwrap();
+        for i in 0..1000u32 {
+            log.append(&[i as u8; 100]).unwrap();
+        }
+
+        assert_eq!(log.chunks.len(), 1);
+        log.close_all().unwrap();
+
+        // only the active chunk is opened on startup
+        let mut log = DiskLog::with_config(dir, config).unwrap();
+        assert_eq!(log.segment_count(), 10);
+        assert_eq!(log.chunks.len(), 1);
+
+        // reads spanning more chunks than the limit still work
+        let (base_offset, relative_offset, count, data) = log.readv(0, 0, 1000 * 100).unwrap();
+        assert_eq!((base_offset, relative_offset, count), (927, 72, 1000));
+        assert_eq!(data[999 * 100], 231);
+
+        // least recently used chunks are closed by the next read
+        assert_eq!(log.read(103, 5).unwrap(), vec![108; 100]);
+        assert_eq!(log.chunks.len(), 3);
+        assert!(log.chunks.contains_key(&103));
+
+        // sealed segments are read-only
+        let chunk = log.chunks.get_mut(&103).unwrap();
+        assert!(chunk.segment.append(&[0; 100]).is_err());
+
+        assert!(log.size() > 1000 * 100);
+        log.truncate(150).unwrap();
+        log.append(&[1; 100]).unwrap();
+        assert_eq!(log.read(103, 47).unwrap(), vec![1; 100]);
+        assert_eq!(log.read(103, 46).unwrap(), vec![149; 100]);
+    }
+
+    #[test]
+    fn read_only_logs_dont_modify_files() {
+        use std::fs;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        // last chunk isn't closed as if it's still being written
+        let mut log = DiskLog::new(dir, 200 * 16, 10 * 1024, 10).unwrap();
+        for i in 0..150u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        log.close(0).unwrap();
+        log.close(103).unwrap();
+        let mut log = DiskLog::new(dir, 200 * 16, 10 * 1024, 10).unwrap();
+        for i in 150..160u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        // records in the write buffer of the segment aren't read
+        let index = dir.join(format!("{:020}.index", 103));
+        let segment = dir.join(format!("{:020}.segment", 103));
+        let snapshot = (fs::read(&index).unwrap(), fs::read(&segment).unwrap());
+        assert_eq!(snapshot.0.len(), 16 + 200 * 16);
+
+        let mut reader = DiskLog::open_read_only(dir).unwrap();
+        assert_eq!(reader.next_offset(), 150);
+        assert_eq!(reader.read(103, 46).unwrap(), vec![149; 100]);
+        assert!(reader.append(&[0; 100]).is_err());
+        assert!(reader.truncate(10).is_err());
+        reader.close_all().unwrap();
+        drop(reader);
+
+        assert_eq!(fs::read(&index).unwrap(), snapshot.0);
+        assert_eq!(fs::read(&segment).unwrap(), snapshot.1);
+
+        log.close_all().unwrap();
+        let mut reader = DiskLog::open_read_only(dir).unwrap();
+        let (_, _, count, _) = reader.readv(0, 0, 200 * 100).unwrap();
+        assert_eq!(count, 160);
+
+        let empty = tempfile::tempdir().unwrap();
+        assert!(DiskLog::open_read_only(empty.path()).is_err());
+    }
+
+    #[test]
+    fn snapshots_dont_change_with_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let backup = tempfile::tempdir().unwrap();
+        let backup = backup.path().join("backup");
+
+        // 0.segment (0 - 102), 103.segment (103 - 205), 206.segment (206 - 249)
+        let mut log = DiskLog::new(dir, 200 * 16, 10 * 1024, 10).unwrap();
+        for i in 0..250u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        log.delete_records_before(10).unwrap();
+        assert_eq!(log.snapshot(&backup).unwrap(), 250);
+        assert!(log.snapshot(&backup).is_err());
+
+        // changes after the snapshot, including sealed chunks
+        log.append(&[1; 100]).unwrap();
+        log.truncate(150).unwrap();
+        log.append(&[2; 100]).unwrap();
+        assert_eq!(log.read(103, 47).unwrap(), vec![2; 100]);
+        log.close_all().unwrap();
+
+        let mut copy = DiskLog::new(&backup, 200 * 16, 10 * 1024, 10).unwrap();
+        assert_eq!(copy.start_offset(), 10);
+        assert_eq!(copy.next_offset(), 250);
+        assert_eq!(copy.read(103, 47).unwrap(), vec![150; 100]);
+        assert_eq!(copy.read(206, 43).unwrap(), vec![249; 100]);
+
+        // copy of the active chunk is ready for appends
+        assert_eq!(copy.append(&[3; 100]).unwrap(), (206, 44));
+    }
+
+    #[test]
+    fn exported_records_are_imported_with_their_offsets() {
+        use super::{Config, IndexInterval};
+
+        let dir = tempfile::tempdir().unwrap();
+        let source_dir = dir.path().join("source");
+        let target_dir = dir.path().join("target");
+
+        let mut source = DiskLog::new(&source_dir, 200 * 16, 10 * 1024, 10).unwrap();
+        for i in 0..250u8 {
+            source.append(&[i; 100]).unwrap();
+        }
+
+        source.delete_records_before(10).unwrap();
+        let mut stream = Vec::new();
+        assert_eq!(source.export(0..200, &mut stream).unwrap(), 190);
+
+        // target has different segment sizes and index layout
+        let config = Config {
+            max_index_size: 1024,
+            max_segment_size: 2 * 1024,
+            max_segments: 100,
+            index_interval: IndexInterval::Records(4),
+            ..Config::default()
+        };
+
+        let mut target = DiskLog::with_config(&target_dir, config.clone()).unwrap();
+        assert_eq!(target.import(&stream[..]).unwrap(), 190);
+        assert_eq!((target.start_offset(), target.next_offset()), (10, 200));
+        assert!(target.segment_count() > source.segment_count());
+
+        // next export continues the log. overlapping exports don't
+        let mut rest = Vec::new();
+        assert_eq!(source.export(200..1000, &mut rest).unwrap(), 50);
+        assert_eq!(target.import(&rest[..]).unwrap(), 50);
+        assert!(target.import(&stream[..]).is_err());
+        target.close_all().unwrap();
+
+        let mut target = DiskLog::with_config(&target_dir, config).unwrap();
+        let mut next = Some((Vec::new(), 0, 0));
+        let mut expected = 10;
+        while let Some((_, base_offset, offset)) = next {
+            next = target.read_next(base_offset, offset).unwrap();
+            if let Some((record, _, _)) = &next {
+                assert_eq!(record, &vec![expected as u8; 100]);
+                expected += 1;
+            }
+        }
+
+        assert_eq!(expected, 250);
+
+        // sparse chunks export the same stream
+        let mut again = Vec::new();
+        assert_eq!(target.export(0..200, &mut again).unwrap(), 190);
+        assert_eq!(again, stream);
+    }
+
+    #[test]
+    fn archived_chunks_are_still_readable() {
+        use super::{ArchiveStore, Config, DirArchive};
+        use std::sync::Arc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let archive_dir = tempfile::tempdir().unwrap();
+        let archive = Arc::new(DirArchive::new(archive_dir.path()).unwrap());
+        let config = Config {
+            max_index_size: 200 * 16,
+            max_segment_size: 10 * 1024,
+            max_segments: 2,
+            archive: Some(archive.clone()),
+            max_archived_segments: 5,
+            ..Config::default()
+        };
+
+        // 103 records a chunk. 3 oldest chunks are deleted, next 5 are archived
+        let mut log = DiskLog::with_config(dir.path(), config.clone()).unwrap();
+        for i in 0..1000u64 {
+            log.append(&[i as u8; 100]).unwrap();
+        }
+
+        assert_eq!(archive.list().unwrap(), vec![309, 412, 515, 618, 721]);
+        assert_eq!((log.head(), log.segment_count()), (309, 7));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 4);
+        assert_eq!(log.read(309, 0).unwrap(), vec![309u64 as u8; 100]);
+        assert_eq!(log.read(721, 102).unwrap(), vec![823u64 as u8; 100]);
+
+        // reads cross from archived chunks to the log directory
+        let (base_offset, offset, count, data) = log.readv(721, 100, 500).unwrap();
+        assert_eq!((base_offset, offset, count), (824, 1, 5));
+        assert_eq!(&data[400..], &[825u64 as u8; 100][..]);
+        assert!(log.truncate(500).is_err());
+        log.close_all().unwrap();
+
+        let mut log = DiskLog::with_config(dir.path(), config).unwrap();
+        assert_eq!((log.head(), log.next_offset()), (309, 1000));
+        assert_eq!(log.read(412, 5).unwrap(), vec![417u64 as u8; 100]);
+
+        log.delete_records_before(600).unwrap();
+        assert_eq!(archive.list().unwrap(), vec![515, 618, 721]);
+        assert_eq!(log.read(618, 0).unwrap(), vec![618u64 as u8; 100]);
+    }
+
+    #[test]
+    fn archived_chunks_are_moved_in_the_background() {
+        use super::{ArchiveStore, Config, DirArchive};
+        use std::sync::Arc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let archive_dir = tempfile::tempdir().unwrap();
+        let archive = Arc::new(DirArchive::new(archive_dir.path()).unwrap());
+        let config = Config {
+            max_index_size: 200 * 16,
+            max_segment_size: 10 * 1024,
+            max_segments: 2,
+            archive: Some(archive.clone()),
+            max_archived_segments: 5,
+            background: true,
+            ..Config::default()
+        };
+
+        // reads of archived chunks wait for the worker to move them
+        let mut log = DiskLog::with_config(dir.path(), config).unwrap();
+        for i in 0..1000u64 {
+            log.append(&[i as u8; 100]).unwrap();
+            if i % 103 == 0 && i >= 206 {
+                let archived = i - 206;
+                assert_eq!(log.read(archived, 0).unwrap(), vec![archived as u8; 100]);
+            }
+        }
+
+        let snapshot = tempfile::tempdir().unwrap();
+        assert_eq!(log.snapshot(snapshot.path()).unwrap(), 1000);
+        log.close_all().unwrap();
+
+        assert_eq!(archive.list().unwrap(), vec![309, 412, 515, 618, 721]);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 4);
+        assert_eq!(log.read(721, 102).unwrap(), vec![823u64 as u8; 100]);
+
+        // snapshots on the filesystem of the archive link archived chunks
+        let mut copy = DiskLog::new(snapshot.path(), 200 * 16, 10 * 1024, 10).unwrap();
+        assert_eq!((copy.head(), copy.next_offset()), (309, 1000));
+        assert_eq!(copy.read(412, 5).unwrap(), vec![417u64 as u8; 100]);
+    }
+
+    #[test]
+    fn chunks_which_fail_to_archive_stay_in_the_log() {
+        use super::{ArchiveStore, Config, DirArchive};
+        use std::path::{Path, PathBuf};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        #[derive(Debug)]
+        struct Flaky {
+            archive: DirArchive,
+            fail: AtomicBool,
+        }
+
+        impl ArchiveStore for Flaky {
+            fn archive(&self, dir: &Path, base_offset: u64) -> io::Result<()> {
+                if self.fail.load(Ordering::SeqCst) {
+                    return Err(io::Error::other("Archive unavailable"));
+                }
+
+                self.archive.archive(dir, base_offset)
+            }
+
+            fn dir(&self, base_offset: u64) -> io::Result<PathBuf> {
+                self.archive.dir(base_offset)
+            }
+
+            fn list(&self) -> io::Result<Vec<u64>> {
+                self.archive.list()
+            }
+
+            fn remove(&self, base_offset: u64) -> io::Result<()> {
+                self.archive.remove(base_offset)
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let archive_dir = tempfile::tempdir().unwrap();
+        let archive = Arc::new(Flaky {
+            archive: DirArchive::new(archive_dir.path()).unwrap(),
+            fail: AtomicBool::new(true),
+        });
+        let config = Config {
+            max_index_size: 200 * 16,
+            max_segment_size: 10 * 1024,
+            max_segments: 2,
+            archive: Some(archive.clone()),
+            background: true,
+            ..Config::default()
+        };
+
+        // 103 records a chunk. roll to 206 moves 0 to the archive in the background
+        let mut log = DiskLog::with_config(dir.path(), config).unwrap();
+        for i in 0..=206u64 {
+            log.append(&[i as u8; 100]).unwrap();
+        }
+
+        assert!(log.sync().is_err());
+        assert_eq!(log.archived, vec![]);
+        assert!(dir.path().join(format!("{:020}.segment", 0)).exists());
+        assert_eq!(log.read(0, 5).unwrap(), vec![5; 100]);
+
+        // next roll archives the chunk again and catches up
+        archive.fail.store(false, Ordering::SeqCst);
+        for i in 207..=309u64 {
+            log.append(&[i as u8; 100]).unwrap();
+        }
+
+        log.sync().unwrap();
+        assert_eq!(log.archived, vec![0, 103]);
+        assert_eq!(archive.list().unwrap(), vec![0, 103]);
+        assert_eq!(log.read(0, 5).unwrap(), vec![5; 100]);
+        assert_eq!(log.read(103, 5).unwrap(), vec![108; 100]);
+    }
+
+    #[test]
+    fn active_indexes_grow_till_they_are_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let index = dir.join(format!("{:020}.index", 0));
+
+        // 64K index holds 4096 entries. segments don't fill up before their index
+        let mut log = DiskLog::new(dir, 64 * 1024, 1024 * 1024, 10).unwrap();
+        assert!(std::fs::metadata(&index).unwrap().len() < 64 * 1024);
+
+        for i in 0..4096u64 {
+            log.append(&[i as u8; 10]).unwrap();
+        }
+
+        assert_eq!(log.segment_count(), 1);
+        assert_eq!(std::fs::metadata(&index).unwrap().len(), 16 + 64 * 1024);
+
+        // next append rolls as the index is full
+        assert_eq!(log.append(&[0; 10]).unwrap(), (4096, 0));
+        assert_eq!(log.read(0, 4095).unwrap(), vec![255; 10]);
+    }
+
+    #[test]
+    fn corrupted_record_headers_are_errors() {
+        use super::{decode_framed_records, Sweep};
+
+        let sweep = Sweep {
+            base_offset: 0,
+            relative_offset: 0,
+            position: 0,
+            size: 15,
+            count: 2,
+        };
+
+        let decode_framed_sweep = |data: &[u8]| {
+            let mut out = Vec::new();
+            decode_framed_records(None, 1024, &sweep, data, |_, record| {
+                out.extend_from_slice(record);
+                Ok(())
+            })
+            .map(|_| out)
+        };
+
+        // 2 records with 5 byte headers
+        let mut data = vec![0, 0, 0, 5, 0, 1, 2, 3, 4, 5, 0, 0, 0, 0, 0];
+        assert_eq!(decode_framed_sweep(&data).unwrap(), vec![1, 2, 3, 4, 5]);
+
+        // lengths past the end of the sweep and cut off headers
+        data[13] = 1;
+        assert!(decode_framed_sweep(&data).is_err());
+        data[3] = 0xff;
+        assert!(decode_framed_sweep(&data).is_err());
+        assert!(decode_framed_sweep(&data[..12]).is_err());
+    }
+
+    #[test]
+    fn reads_at_an_empty_tail_chunk_dont_panic() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let mut log = DiskLog::new(dir, 200 * 16, 10 * 1024, 10).unwrap();
+        match log.readv(0, 0, 1000) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => (),
+            _ => panic!("Expecting EOF on an empty log"),
+        }
+
+        // 0.segment (0 - 102), 103.segment (empty after truncation)
+        for i in 0..110u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        log.truncate(103).unwrap();
+        let (base_offset, offset, count, data) = log.readv(103, 0, 1000).unwrap();
+        assert_eq!((base_offset, offset, count, data.len()), (0, 102, 0, 0));
+        assert!(log.read_next(103, 0).unwrap().is_none());
+
+        // cursors of reads which reach the empty chunk stay at the last record
+        let (base_offset, offset, count, _) = log.readv(0, 100, 1000).unwrap();
+        assert_eq!((base_offset, offset, count), (0, 102, 3));
+
+        // nothing is left before the tail once all the records are deleted
+        log.delete_records_before(103).unwrap();
+        assert!(log.readv(103, 0, 1000).is_err());
+        assert_eq!(log.append(&[1; 100]).unwrap(), (103, 0));
+        assert_eq!(log.readv(103, 0, 1000).unwrap().2, 1);
+    }
+
+    #[test]
+    fn reads_cross_gaps_left_by_removed_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        // 0.segment (0 - 102), 103.segment (103 - 205), 206.segment ...
+        let mut log = DiskLog::new(dir, 200 * 16, 10 * 1024, 10).unwrap();
+        for i in 0..300u64 {
+            log.append(&[i as u8; 100]).unwrap();
+        }
+
+        log.remove(103).unwrap();
+        let (base_offset, offset, count, data) = log.readv(0, 101, 500).unwrap();
+        assert_eq!((base_offset, offset, count), (206, 2, 5));
+        assert_eq!(&data[200..300], &[206; 100][..]);
+
+        let (record, base_offset, offset) = log.read_next(0, 103).unwrap().unwrap();
+        assert_eq!((record, base_offset, offset), (vec![206; 100], 206, 1));
+    }
+
+    #[test]
+    fn reads_skip_quarantined_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        // 0.segment (0 - 102), 103.segment (103 - 205), 206.segment ...
+        let mut log = DiskLog::new(dir, 200 * 16, 10 * 1024, 10).unwrap();
+        for i in 0..300u64 {
+            log.append(&[i as u8; 100]).unwrap();
+        }
+
+        log.close_all().unwrap();
+        std::fs::remove_file(dir.join(format!("{:020}.index", 103))).unwrap();
+
+        let mut log = DiskLog::new(dir, 200 * 16, 10 * 1024, 10).unwrap();
+        assert_eq!(log.segment_count(), 2);
+        let (base_offset, offset, count, _) = log.readv(0, 102, 200).unwrap();
+        assert_eq!((base_offset, offset, count), (206, 0, 2));
+
+        // last record before an empty tail chunk is the last one of the previous chunk
+        log.truncate(206).unwrap();
+        let (base_offset, offset, count, _) = log.readv(206, 0, 1000).unwrap();
+        assert_eq!((base_offset, offset, count), (0, 102, 0));
+    }
+
+    #[test]
+    fn stray_files_dont_stop_the_log_from_opening() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let mut log = DiskLog::new(dir, 100 * 16, 10 * 1024, 10).unwrap();
+        log.append(&[1; 100]).unwrap();
+        log.close_all().unwrap();
+
+        std::fs::write(dir.join(".DS_Store"), b"junk").unwrap();
+        std::fs::write(dir.join("LOCK"), b"").unwrap();
+        let mut log = DiskLog::new(dir, 100 * 16, 10 * 1024, 10).unwrap();
+        assert_eq!(log.segment_count(), 1);
+        assert_eq!(log.read(0, 0).unwrap(), vec![1; 100]);
+    }
+
+    #[test]
+    fn legacy_chunks_without_headers_are_still_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        // chunk written before files had headers. 5 records of 100 bytes
+        let (mut segment, mut index) = (Vec::new(), Vec::new());
+        for i in 0..5u64 {
+            segment.extend_from_slice(&[i as u8; 100]);
+            index.extend_from_slice(&(i * 100).to_be_bytes());
+            index.extend_from_slice(&100u64.to_be_bytes());
+        }
+
+        std::fs::write(dir.join(format!("{:020}.segment", 0)), segment).unwrap();
+        std::fs::write(dir.join(format!("{:020}.index", 0)), index).unwrap();
+
+        // legacy chunk is appended in the old format. next chunk gets headers
+        let mut log = DiskLog::new(dir, 100 * 16, 1024, 10).unwrap();
+        for i in 5..15u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+
+        log.close_all().unwrap();
+        let legacy = std::fs::read(dir.join(format!("{:020}.segment", 0))).unwrap();
+        let segment = std::fs::read(dir.join(format!("{:020}.segment", 11))).unwrap();
+        assert_eq!(legacy.len(), 11 * 100);
+        assert_eq!(&segment[..4], b"SSEG");
+
+        let mut log = DiskLog::new(dir, 100 * 16, 1024, 10).unwrap();
+        for i in 0..15u64 {
+            let (base_offset, offset) = if i < 11 { (0, i) } else { (11, i - 11) };
+            assert_eq!(log.read(base_offset, offset).unwrap(), vec![i as u8; 100]);
+        }
+    }
+
+    #[test]
+    fn records_bigger_than_a_segment_get_a_segment_of_their_own() {
+        use super::Config;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let config = Config {
+            max_index_size: 100 * 16,
+            max_segment_size: 10 * 1024,
+            max_segments: 10,
+            max_record_size: 100 * 1024,
+            ..Config::default()
+        };
+
+        let mut log = DiskLog::with_config(dir, config).unwrap();
+        assert!(log.append(&vec![0u8; 101 * 1024]).is_err());
+
+        // 0.segment (0 - 4), 5.segment (5), 6.segment (6 - 7)
+        let mut offsets = Vec::new();
+        for i in 0..8u8 {
+            let size = if i == 5 { 50 * 1024 } else { 1024 };
+            offsets.push(log.append(&vec![i; size]).unwrap());
+        }
+
+        assert_eq!(offsets[4], (0, 4));
+        assert_eq!(offsets[5], (5, 0));
+        assert_eq!(offsets[6], (6, 0));
+        assert_eq!(log.read(5, 0).unwrap(), vec![5u8; 50 * 1024]);
+
+        let (base_offset, relative_offset, count, data) = log.readv(0, 0, 100 * 1024).unwrap();
+        assert_eq!((base_offset, relative_offset, count), (6, 1, 8));
+        assert_eq!(data.len(), 57 * 1024);
+    }
+
     #[test]
     fn vectored_read_more_than_full_chomp_works_as_expected() {
         let dir = tempfile::tempdir().unwrap();
@@ -0,0 +1,313 @@
+use super::{Config, DiskLog};
+
+use fnv::FnvHasher;
+use std::fs;
+use std::hash::Hasher;
+use std::io;
+use std::path::PathBuf;
+
+/// A log split into a fixed number of partitions. Every partition is an
+/// independent `DiskLog` in `dir/<partition>` with its own offsets. Records
+/// are routed to partitions by hash of their key so that records of a key
+/// stay ordered while different keys can be consumed in parallel
+pub struct PartitionedLog {
+    dir: PathBuf,
+    partitions: Vec<DiskLog>,
+    /// Next record of every partition which `readv_merged` read but didn't
+    /// return, along with the cursor it was read at
+    read_ahead: Vec<Option<Head>>,
+}
+
+/// Record of a partition which is read but not returned yet
+struct Head {
+    /// Cursor the record is read at
+    cursor: (u64, u64),
+    record: Vec<u8>,
+    base_offset: u64,
+    /// Relative offset of the next record
+    next: u64,
+}
+
+impl PartitionedLog {
+    /// Creates or reopens a partitioned log. Partition count of an existing log
+    /// can't be changed as that changes which partition a key is routed to
+    pub fn new<P: Into<PathBuf>>(
+        dir: P,
+        partitions: usize,
+        config: Config,
+    ) -> io::Result<PartitionedLog> {
+        let dir = dir.into();
+        if partitions == 0 {
+            panic!("log should have at least one partition")
+        }
+
+        fs::create_dir_all(&dir)?;
+        let mut existing = 0;
+        for entry in fs::read_dir(&dir)? {
+            if entry?.path().is_dir() {
+                existing += 1;
+            }
+        }
+
+        if existing != 0 && existing != partitions {
+            let e = format!(
+                "Log has {} partitions. Can't reopen it with {} partitions",
+                existing, partitions
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        }
+
+        let mut logs = Vec::with_capacity(partitions);
+        for partition in 0..partitions {
+            let name = partition.to_string();
+            let log = DiskLog::with_config(dir.join(&name), config.scope(&name)?)?;
+            logs.push(log);
+        }
+
+        let log = PartitionedLog {
+            dir,
+            read_ahead: logs.iter().map(|_| None).collect(),
+            partitions: logs,
+        };
+
+        Ok(log)
+    }
+
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    /// Number of partitions
+    pub fn partitions(&self) -> usize {
+        self.partitions.len()
+    }
+
+    /// Partition which records of this key are routed to
+    pub fn partition_for(&self, key: &[u8]) -> usize {
+        let mut hasher = FnvHasher::default();
+        hasher.write(key);
+        (hasher.finish() % self.partitions.len() as u64) as usize
+    }
+
+    /// Log of the given partition
+    pub fn partition(&mut self, partition: usize) -> io::Result<&mut DiskLog> {
+        // records read ahead might be truncated through the log
+        if let Some(read_ahead) = self.read_ahead.get_mut(partition) {
+            *read_ahead = None;
+        }
+
+        self.log(partition)
+    }
+
+    fn log(&mut self, partition: usize) -> io::Result<&mut DiskLog> {
+        match self.partitions.get_mut(partition) {
+            Some(log) => Ok(log),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid partition",
+            )),
+        }
+    }
+
+    /// Appends record to the partition of the key. Returns the partition along
+    /// with base offset and relative offset of the record in that partition
+    pub fn append(&mut self, key: &[u8], record: &[u8]) -> io::Result<(usize, u64, u64)> {
+        let partition = self.partition_for(key);
+        self.append_to(partition, record)
+    }
+
+    /// Appends record to an explicit partition
+    pub fn append_to(&mut self, partition: usize, record: &[u8]) -> io::Result<(usize, u64, u64)> {
+        let (base_offset, offset) = self.log(partition)?.append(record)?;
+        Ok((partition, base_offset, offset))
+    }
+
+    /// Reads a record from a partition. See `DiskLog::read`
+    pub fn read(&mut self, partition: usize, base_offset: u64, offset: u64) -> io::Result<Vec<u8>> {
+        self.log(partition)?.read(base_offset, offset)
+    }
+
+    /// Reads multiple records from a partition. See `DiskLog::readv`
+    pub fn readv(
+        &mut self,
+        partition: usize,
+        base_offset: u64,
+        relative_offset: u64,
+        size: u64,
+    ) -> io::Result<(u64, u64, u64, Vec<u8>)> {
+        self.log(partition)?
+            .readv(base_offset, relative_offset, size)
+    }
+
+    /// Reads records of all the partitions interleaved in the order of their
+    /// timestamps, which are extracted from records by `timestamp`. `cursors`
+    /// hold base offset and relative offset to read from in every partition,
+    /// like the arguments of `readv`. Cursors of partitions which had records
+    /// read are moved to the record after the last one read, so they can be
+    /// passed back as they are to continue reading. Reading stops after `size`
+    /// bytes are collected or when all the partitions are drained. Returns
+    /// records along with the partition they belong to
+    pub fn readv_merged<F>(
+        &mut self,
+        cursors: &mut [(u64, u64)],
+        size: u64,
+        timestamp: F,
+    ) -> io::Result<Vec<(usize, Vec<u8>)>>
+    where
+        F: Fn(&[u8]) -> u64,
+    {
+        if cursors.len() != self.partitions.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Expecting a cursor per partition",
+            ));
+        }
+
+        // Next unconsumed record of every partition along with the cursor it's
+        // read at. Records read ahead by the previous call aren't read again
+        let mut heads = Vec::with_capacity(self.partitions.len());
+        for (partition, &cursor) in cursors.iter().enumerate() {
+            let head = match self.read_ahead[partition].take() {
+                Some(head) if head.cursor == cursor => Some(head),
+                _ => self.read_head(partition, cursor)?,
+            };
+
+            heads.push(head);
+        }
+
+        let mut out = Vec::new();
+        let mut out_size = 0;
+        while out_size < size {
+            let next = heads
+                .iter()
+                .enumerate()
+                .filter_map(|(partition, head)| head.as_ref().map(|h| (partition, h)))
+                .min_by_key(|(_, head)| timestamp(&head.record))
+                .map(|(partition, _)| partition);
+
+            let partition = match next {
+                Some(p) => p,
+                None => break,
+            };
+
+            let head = heads[partition].take().unwrap();
+            cursors[partition] = (head.base_offset, head.next);
+            heads[partition] = self.read_head(partition, (head.base_offset, head.next))?;
+            out_size += head.record.len() as u64;
+            out.push((partition, head.record));
+        }
+
+        self.read_ahead = heads;
+        Ok(out)
+    }
+
+    fn read_head(&mut self, partition: usize, cursor: (u64, u64)) -> io::Result<Option<Head>> {
+        let head = self.partitions[partition]
+            .read_next(cursor.0, cursor.1)?
+            .map(|(record, base_offset, next)| Head {
+                cursor,
+                record,
+                base_offset,
+                next,
+            });
+
+        Ok(head)
+    }
+
+    pub fn close_all(&mut self) -> io::Result<()> {
+        for log in self.partitions.iter_mut() {
+            log.close_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PartitionedLog;
+    use crate::disk::Config;
+    use byteorder::{BigEndian, ByteOrder};
+    use pretty_assertions::assert_eq;
+
+    fn config() -> Config {
+        Config {
+            max_index_size: 100 * 16,
+            max_segment_size: 1024,
+            max_segments: 100,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn records_of_a_key_go_to_the_same_partition() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = PartitionedLog::new(dir.path(), 4, config()).unwrap();
+
+        let partition = log.partition_for(b"device-1");
+        for i in 0..10u8 {
+            let (p, base_offset, offset) = log.append(b"device-1", &[i; 100]).unwrap();
+            assert_eq!(p, partition);
+
+            let record = log.read(p, base_offset, offset).unwrap();
+            assert_eq!(record[0], i);
+        }
+
+        let (p, _, offset) = log.append_to(3, b"hello").unwrap();
+        assert_eq!((p, offset), (3, if partition == 3 { 10 } else { 0 }));
+        assert!(log.append_to(4, b"hello").is_err());
+    }
+
+    #[test]
+    fn reopening_with_different_partition_count_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = PartitionedLog::new(dir.path(), 4, config()).unwrap();
+        log.append(b"key", b"hello").unwrap();
+        log.close_all().unwrap();
+
+        assert!(PartitionedLog::new(dir.path(), 2, config()).is_err());
+        let mut log = PartitionedLog::new(dir.path(), 4, config()).unwrap();
+        let partition = log.partition_for(b"key");
+        assert_eq!(log.read(partition, 0, 0).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn merged_reads_interleave_partitions_by_timestamp() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = PartitionedLog::new(dir.path(), 3, config()).unwrap();
+
+        // records are 100 bytes with timestamp in the first 8 bytes. 20 records per
+        // partition. 0.segment (0 - 10), 11.segment (11 - 19). partition of every
+        // record is picked round robin
+        for timestamp in 0..60u64 {
+            let mut record = vec![0; 100];
+            BigEndian::write_u64(&mut record, timestamp);
+            log.append_to(timestamp as usize % 3, &record).unwrap();
+        }
+
+        let timestamp = |record: &[u8]| BigEndian::read_u64(record);
+        let mut cursors = vec![(0, 0); 3];
+        let mut expected = 0;
+        loop {
+            let records = log.readv_merged(&mut cursors, 1000, timestamp).unwrap();
+            if records.is_empty() {
+                break;
+            }
+
+            for (partition, record) in records {
+                assert_eq!(timestamp(&record), expected);
+                assert_eq!(partition, expected as usize % 3);
+                expected += 1;
+            }
+
+            // cursors point to the record after the last read one
+            assert_eq!(
+                cursors[(expected as usize - 1) % 3].1,
+                (expected - 1) / 3 % 11 + 1
+            );
+        }
+
+        assert_eq!(expected, 60);
+        assert_eq!(cursors, vec![(11, 9), (11, 9), (11, 9)]);
+    }
+}
@@ -0,0 +1,343 @@
+use super::header::{self, HEADER_WIDTH, INDEX_MAGIC, SEGMENT_MAGIC};
+use super::index::{Index, IndexEncoding};
+use super::segment::FRAMED;
+use super::worker::DELETED;
+use super::START_OFFSET_FILE;
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Directory orphans with records are moved to
+const QUARANTINE: &str = "quarantine";
+
+/// Scans the log directory and returns sorted base offsets of chunks which have
+/// both an index and a segment. Files which don't belong to the log are ignored.
+/// Orphan indexes and segments without records are deleted and orphan segments
+/// with self delimiting records get their index rebuilt. Rest of the orphans are
+/// moved to the quarantine directory so that the log can still be opened, and
+/// reads skip their records. Scans fail when a quarantined chunk is after the
+/// last chunk as appends would reuse its offsets.
+///
+/// Read-only scans don't touch any files. They skip orphans and chunks with
+/// empty files
+pub(crate) fn scan(
+    dir: &Path,
+    max_index_size: u64,
+    encoding: IndexEncoding,
+    read_only: bool,
+) -> io::Result<Vec<u64>> {
+    // (index, segment) of every base offset
+    let mut files: BTreeMap<u64, (bool, bool)> = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+
+        // start offset and its temporary file
+        let name = path.file_name().and_then(|name| name.to_str());
+        if name.is_some_and(|name| name.starts_with(START_OFFSET_FILE)) {
+            continue;
+        }
+
+        // finish deletions which were interrupted
+        if path.extension().and_then(|extension| extension.to_str()) == Some(DELETED) {
+            if !read_only {
+                fs::remove_file(&path)?;
+            }
+
+            continue;
+        }
+
+        let stem = path.file_stem().and_then(|stem| stem.to_str());
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        let base_offset = match stem.map(|stem| (stem.parse::<u64>(), stem.len())) {
+            Some((Ok(base_offset), 20)) => base_offset,
+            _ => {
+                warn!("Ignoring unknown file {:?} in log directory", path);
+                continue;
+            }
+        };
+
+        let chunk = files.entry(base_offset).or_default();
+        match extension {
+            Some("index") => chunk.0 = true,
+            Some("segment") => chunk.1 = true,
+            _ => warn!("Ignoring unknown file {:?} in log directory", path),
+        }
+    }
+
+    let mut base_offsets = Vec::new();
+    for (base_offset, chunk) in files {
+        let found = match chunk {
+            (true, true) if read_only => has_contents(dir, base_offset)?,
+            (_, _) if read_only => {
+                warn!(
+                    "Ignoring orphan file of chunk {} in log directory",
+                    base_offset
+                );
+                false
+            }
+            (true, true) => true,
+            (true, false) => orphan_index(dir, base_offset)?,
+            (false, true) => orphan_segment(dir, base_offset, max_index_size, encoding)?,
+            (false, false) => false,
+        };
+
+        if found {
+            base_offsets.push(base_offset);
+        }
+    }
+
+    if !read_only {
+        check_quarantine(dir, base_offsets.last().copied())?;
+    }
+
+    Ok(base_offsets)
+}
+
+/// Fails when a quarantined chunk is after the last chunk of the log. Offsets of
+/// its records would be given to new records
+fn check_quarantine(dir: &Path, last: Option<u64>) -> io::Result<()> {
+    let quarantine = dir.join(QUARANTINE);
+    if !quarantine.is_dir() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(&quarantine)? {
+        let path = entry?.path();
+        let stem = path.file_stem().and_then(|stem| stem.to_str());
+        let base_offset = match stem.and_then(|stem| stem.parse::<u64>().ok()) {
+            Some(base_offset) => base_offset,
+            None => continue,
+        };
+
+        if last.is_none_or(|last| base_offset > last) {
+            let e = format!(
+                "Quarantined chunk {} is after the last chunk. Appends would reuse its offsets",
+                base_offset
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether both the files of a chunk have something in them. Files of a chunk
+/// which is just being created might still be empty
+fn has_contents(dir: &Path, base_offset: u64) -> io::Result<bool> {
+    for extension in ["index", "segment"] {
+        let path = dir.join(format!("{:020}.{}", base_offset, extension));
+        if fs::metadata(&path)?.len() == 0 {
+            warn!("Ignoring empty file {:?} in log directory", path);
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Deletes an index without records, e.g one which is preallocated or staged
+/// and only has zeros after its header. Indexes with records can't be used
+/// without their segment and are quarantined
+fn orphan_index(dir: &Path, base_offset: u64) -> io::Result<bool> {
+    let file_name = format!("{:020}.index", base_offset);
+    let path = dir.join(&file_name);
+    let mut file = OpenOptions::new().read(true).open(&path)?;
+    let empty = match header::read(&mut file, INDEX_MAGIC, base_offset) {
+        Ok(header) => {
+            let start = if header.is_some() { HEADER_WIDTH } else { 0 };
+            let mut entries = Vec::new();
+            file.seek(SeekFrom::Start(start))?;
+            file.read_to_end(&mut entries)?;
+            entries.iter().all(|byte| *byte == 0)
+        }
+        Err(_) => false,
+    };
+
+    if empty {
+        warn!("Deleting empty index {:?} without a segment", path);
+        fs::remove_file(&path)?;
+    } else {
+        quarantine(dir, &file_name)?;
+    }
+
+    Ok(false)
+}
+
+/// Deletes a segment without records and rebuilds the index of a segment whose
+/// records are preceded by headers. Rest of the segments are quarantined
+fn orphan_segment(
+    dir: &Path,
+    base_offset: u64,
+    max_index_size: u64,
+    encoding: IndexEncoding,
+) -> io::Result<bool> {
+    let file_name = format!("{:020}.segment", base_offset);
+    let path = dir.join(&file_name);
+    let mut file = OpenOptions::new().read(true).open(&path)?;
+    let len = file.metadata()?.len();
+    let header = header::read(&mut file, SEGMENT_MAGIC, base_offset)
+        .ok()
+        .flatten();
+
+    if len == 0 || (len == HEADER_WIDTH && header.is_some()) {
+        warn!("Deleting empty segment {:?} without an index", path);
+        fs::remove_file(&path)?;
+        return Ok(false);
+    }
+
+    match header {
+        // first record of a segment is always indexed. chunk finds the rest
+        Some((_, flags)) if flags & FRAMED != 0 => {
+            warn!("Rebuilding index of segment {:?}", path);
+            let mut index = Index::open(dir, base_offset, max_index_size, false, encoding, true)?;
+            index.write(0, 0, 0)?;
+            index.close()?;
+            Ok(true)
+        }
+        _ => {
+            quarantine(dir, &file_name)?;
+            Ok(false)
+        }
+    }
+}
+
+fn quarantine(dir: &Path, file_name: &str) -> io::Result<()> {
+    let quarantine = dir.join(QUARANTINE);
+    warn!(
+        "Moving orphan {:?} to {:?}. Its records are skipped",
+        file_name, quarantine
+    );
+    fs::create_dir_all(&quarantine)?;
+    fs::rename(dir.join(file_name), quarantine.join(file_name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::scan;
+    use crate::disk::chunk::Chunk;
+    use crate::disk::index::{IndexEncoding, IndexInterval};
+    use crate::disk::segment::SegmentOptions;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+
+    #[test]
+    fn only_complete_chunks_are_picked_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let encoding = IndexEncoding::Wide;
+
+        for (base_offset, interval) in [(0, IndexInterval::Record), (10, IndexInterval::Bytes(100))]
+        {
+            let mut chunk = Chunk::new(
+                dir,
+                base_offset,
+                1024,
+                true,
+                interval,
+                encoding,
+                SegmentOptions::default(),
+            )
+            .unwrap();
+            for i in 0..10u8 {
+                chunk.append(&[i; 100], 0).unwrap();
+            }
+
+            chunk.close().unwrap();
+        }
+
+        // stray files and directories
+        fs::write(dir.join(".DS_Store"), b"junk").unwrap();
+        fs::write(dir.join("LOCK"), b"").unwrap();
+        fs::write(dir.join("20.segment.tmp"), b"").unwrap();
+        fs::create_dir(dir.join("30.index")).unwrap();
+
+        // empty orphans. preallocated indexes only have zeros
+        fs::write(dir.join(format!("{:020}.index", 40)), b"").unwrap();
+        fs::write(dir.join(format!("{:020}.segment", 50)), b"").unwrap();
+        fs::write(dir.join(format!("{:020}.index", 70)), [0; 1024]).unwrap();
+
+        // orphans with records. dense segment can't be reindexed
+        fs::rename(
+            dir.join(format!("{:020}.index", 0)),
+            dir.join(format!("{:020}.index", 5)),
+        )
+        .unwrap();
+        fs::remove_file(dir.join(format!("{:020}.index", 10))).unwrap();
+
+        // read-only scans leave everything as is
+        let base_offsets = scan(dir, 1024, encoding, true).unwrap();
+        assert_eq!(base_offsets, vec![]);
+        assert!(dir.join(format!("{:020}.index", 40)).exists());
+
+        let base_offsets = scan(dir, 1024, encoding, false).unwrap();
+        assert_eq!(base_offsets, vec![10]);
+
+        assert!(!dir.join(format!("{:020}.index", 40)).exists());
+        assert!(!dir.join(format!("{:020}.segment", 50)).exists());
+        assert!(!dir.join(format!("{:020}.index", 70)).exists());
+        assert!(dir
+            .join("quarantine")
+            .join(format!("{:020}.index", 5))
+            .exists());
+        assert!(dir
+            .join("quarantine")
+            .join(format!("{:020}.segment", 0))
+            .exists());
+
+        // sparse index of the framed segment is rebuilt
+        let mut chunk = Chunk::new(
+            dir,
+            10,
+            1024,
+            true,
+            IndexInterval::Record,
+            encoding,
+            SegmentOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(chunk.count(), 10);
+        assert_eq!(chunk.entry(9).unwrap(), (9 * 105 + 5, 100, 0));
+    }
+
+    #[test]
+    fn quarantined_chunks_after_the_last_chunk_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let encoding = IndexEncoding::Wide;
+
+        for base_offset in [0, 10] {
+            let mut chunk = Chunk::new(
+                dir,
+                base_offset,
+                1024,
+                true,
+                IndexInterval::Record,
+                encoding,
+                SegmentOptions::default(),
+            )
+            .unwrap();
+            for i in 0..10u8 {
+                chunk.append(&[i; 100], 0).unwrap();
+            }
+
+            chunk.close().unwrap();
+        }
+
+        // appends after chunk 0 would reuse offsets of the quarantined chunk
+        fs::remove_file(dir.join(format!("{:020}.segment", 10))).unwrap();
+        assert!(scan(dir, 1024, encoding, false).is_err());
+        assert!(dir
+            .join("quarantine")
+            .join(format!("{:020}.index", 10))
+            .exists());
+        assert!(scan(dir, 1024, encoding, false).is_err());
+
+        // read-only scans can't append
+        assert_eq!(scan(dir, 1024, encoding, true).unwrap(), vec![0]);
+    }
+}
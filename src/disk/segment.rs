@@ -1,68 +1,244 @@
+#[cfg(target_os = "linux")]
+use super::direct::{Direct, BLOCK};
+use super::header::{self, SEGMENT_MAGIC};
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+use super::uring::{Ring, RingWriter};
+use byteorder::{BigEndian, ByteOrder};
 use std::fs::{File, OpenOptions};
-use std::io::{self, BufWriter, Write};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::path::PathBuf;
 
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+use std::os::unix::io::RawFd;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+
+/// Width of the length and flags which precede records in segments with a
+/// sparse index. These headers make records self delimiting so that records
+/// which aren't indexed can be found by scanning from an indexed record
+pub const HEADER_WIDTH: u64 = 5;
+
+/// File header flag of segments whose records are preceded by a header
+pub(crate) const FRAMED: u16 = 0b1;
+
+/// How segments are written
+#[derive(Debug, Clone, Default)]
+pub struct SegmentOptions {
+    /// Bytes of records to allocate disk space for upfront. Linux only
+    pub preallocate: Option<u64>,
+    /// Bypasses the page cache with `O_DIRECT`. Linux only
+    pub direct: bool,
+    /// Opens the segment only for reads. Sealed segments don't need a write
+    /// buffer
+    pub read_only: bool,
+    /// Writes buffered records through io_uring. Not used with direct I/O
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    pub(crate) ring: Option<Ring>,
+}
+
+/// Write path of a segment
+enum Writer {
+    /// Segment is only read. Writes fail
+    ReadOnly,
+    Buffered(BufWriter<File>),
+    #[cfg(target_os = "linux")]
+    Direct(Direct),
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    Ring(RingWriter),
+}
+
+impl Writer {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Writer::ReadOnly => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Segment is read-only",
+            )),
+            Writer::Buffered(writer) => writer.write_all(data),
+            #[cfg(target_os = "linux")]
+            Writer::Direct(writer) => writer.write_all(data),
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            Writer::Ring(writer) => writer.write_all(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::ReadOnly => Ok(()),
+            Writer::Buffered(writer) => writer.flush(),
+            #[cfg(target_os = "linux")]
+            Writer::Direct(writer) => writer.flush(),
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            Writer::Ring(writer) => writer.flush(),
+        }
+    }
+
+    fn is_direct(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        if let Writer::Direct(_) = self {
+            return true;
+        }
+
+        false
+    }
+}
+
 /// Segment of a disk. Writes go through a buffer writers to
 /// reduce number of system calls. Reads are directly read from
 /// the file as seek on buffer reader will dump the buffer anyway
 /// Also multiple readers might be operating on a given segment
 /// which makes the cursor movement very dynamic
+///
+/// Positions of records don't include the file header. Segments written with
+/// direct I/O are bigger than their records till they are closed. Preallocated
+/// space is past the end of the file and doesn't change its length
 pub struct Segment {
     file: File,
-    writer: BufWriter<File>,
+    writer: Writer,
+    /// Size of the file header. 0 for legacy segments
+    header_width: u64,
+    framed: bool,
+    preallocated: bool,
+    read_only: bool,
     size: u64,
     next_offset: u64,
-    max_record_size: u64,
 }
 
 impl Segment {
+    #[cfg(test)]
+    pub fn new<P: AsRef<Path>>(dir: P, base_offset: u64) -> io::Result<Segment> {
+        Segment::open(dir, base_offset, false, false, SegmentOptions::default())
+    }
+
     // TODO next offset should be initialized correctly for segments which are reconstructed. `append`
     // TODO without this will result in wrong offsets in the return position
-    pub fn new<P: AsRef<Path>>(dir: P, base_offset: u64) -> io::Result<Segment> {
+    /// Opens segment of a chunk. Segments of legacy chunks don't have a header.
+    /// `framed` is only used for new segments. Existing segments are read as
+    /// described by their header
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        base_offset: u64,
+        legacy: bool,
+        framed: bool,
+        options: SegmentOptions,
+    ) -> io::Result<Segment> {
         let file_name = format!("{:020}.segment", base_offset);
         let file_path: PathBuf = dir.as_ref().join(file_name);
-        let file = OpenOptions::new()
+
+        let preallocate = options.preallocate.filter(|_| cfg!(target_os = "linux"));
+        let read_only = options.read_only;
+        let mut file = OpenOptions::new()
             .read(true)
-            .append(true)
-            .create(true)
+            .write(!read_only)
+            .append(!read_only)
+            .create(!read_only)
             .open(&file_path)?;
         let metadata = file.metadata()?;
 
+        // Contents of legacy segments can't be told apart from a header. Chunk
+        // decides whether a segment is legacy based on its index
+        let (framed, header_width) = if legacy {
+            (framed, 0)
+        } else if metadata.len() == 0 {
+            let flags = if framed { FRAMED } else { 0 };
+            header::write(&mut file, SEGMENT_MAGIC, 0, flags, base_offset)?;
+            (framed, header::HEADER_WIDTH)
+        } else {
+            match header::read(&mut file, SEGMENT_MAGIC, base_offset)? {
+                Some((_, flags)) => (flags & FRAMED != 0, header::HEADER_WIDTH),
+                None => {
+                    let e = format!("Segment {} doesn't have a header", base_offset);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+            }
+        };
+
         // 1MB buffer size
         // NOTE write perf is only increasing till a certain buffer size. bigger sizes after that is causing a degrade
-        let buf = BufWriter::with_capacity(1024 * 1024, file.try_clone()?);
-        let size = metadata.len();
+        // Sealed segments don't get a write buffer. With direct I/O they are still
+        // read around the page cache
+        let size = file.metadata()?.len() - header_width;
+        let writer = if options.read_only {
+            match options.direct {
+                #[cfg(target_os = "linux")]
+                true => match Direct::open_read_only(&file_path) {
+                    Ok(direct) => Writer::Direct(direct),
+                    Err(e) => {
+                        warn!(
+                            "Direct I/O unavailable for {:?}. Error = {:?}",
+                            file_path, e
+                        );
+                        Writer::ReadOnly
+                    }
+                },
+                _ => Writer::ReadOnly,
+            }
+        } else {
+            let writer = file.try_clone()?;
+            if let Some(len) = preallocate {
+                allocate(&file, header_width + size.max(len))?;
+            }
+
+            match options.direct {
+                #[cfg(target_os = "linux")]
+                true => match Direct::open(&file_path, header_width + size) {
+                    Ok(direct) => Writer::Direct(direct),
+                    Err(e) => {
+                        warn!(
+                            "Direct I/O unavailable for {:?}. Error = {:?}",
+                            file_path, e
+                        );
+                        Writer::Buffered(BufWriter::with_capacity(1024 * 1024, writer))
+                    }
+                },
+                #[cfg(all(target_os = "linux", feature = "io_uring"))]
+                _ if options.ring.is_some() => {
+                    // positions of writes are ignored in append mode
+                    let ring = options.ring.unwrap();
+                    let writer = OpenOptions::new().write(true).open(&file_path)?;
+                    Writer::Ring(RingWriter::new(ring, writer, header_width + size))
+                }
+                _ => Writer::Buffered(BufWriter::with_capacity(1024 * 1024, writer)),
+            }
+        };
 
         let segment = Segment {
             file,
-            writer: buf,
+            writer,
+            header_width,
+            framed,
+            preallocated: preallocate.is_some(),
+            read_only,
             size,
             next_offset: 0,
-            max_record_size: 10 * 1024,
         };
 
         Ok(segment)
     }
 
+    /// Size of the records (along with their headers)
     pub fn size(&self) -> u64 {
         self.size
     }
 
+    /// Size of the file without preallocated space and padding. Includes the
+    /// file header
+    pub fn file_size(&self) -> u64 {
+        self.header_width + self.size
+    }
+
+    /// Whether records of the segment are preceded by a header
+    pub fn is_framed(&self) -> bool {
+        self.framed
+    }
+
     pub fn set_next_offset(&mut self, next_offset: u64) {
         self.next_offset = next_offset;
     }
 
     /// Appends record to the file and return its offset
     pub fn append(&mut self, record: &[u8]) -> io::Result<(u64, u64)> {
-        let record_size = record.len() as u64;
-        if record_size > self.max_record_size {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Max record size exceeded",
-            ));
-        }
-
         // append record and increment size. cursor is moved to the end as per the docs
         // so we probably don't have to worry about reading and writing simultaneously
         self.writer.write_all(record)?;
@@ -76,19 +252,62 @@ impl Segment {
         Ok((offset, position))
     }
 
+    /// Appends record along with a header carrying its length and flags. Returns
+    /// offset of the record and position of the header
+    pub fn append_with_header(&mut self, record: &[u8], flags: u8) -> io::Result<(u64, u64)> {
+        if record.len() as u64 > u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Record too big for a header",
+            ));
+        }
+
+        let mut header = [0; HEADER_WIDTH as usize];
+        BigEndian::write_u32(&mut header[..4], record.len() as u32);
+        header[4] = flags;
+
+        self.writer.write_all(&header)?;
+        self.size += HEADER_WIDTH;
+        let (offset, position) = self.append(record)?;
+        Ok((offset, position - HEADER_WIDTH))
+    }
+
+    /// Reads header at the given position and returns length and flags of the record
+    pub fn read_header(&mut self, position: u64) -> io::Result<(u64, u8)> {
+        if position + HEADER_WIDTH > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Reading header at an invalid position",
+            ));
+        }
+
+        let mut header = [0; HEADER_WIDTH as usize];
+        self.read(position, &mut header)?;
+        Ok(parse_header(&header))
+    }
+
     /// Reads to fill the complete buffer. Returns number of bytes read
     pub fn read(&mut self, position: u64, buf: &mut [u8]) -> io::Result<u64> {
         // TODO: No need to flush segments which are already filled. Make this conditional and check perf
-        self.writer.flush()?;
+        // Direct I/O reads records which aren't written yet from its buffer
+        if !self.writer.is_direct() {
+            self.writer.flush()?;
+        }
+
         self.read_at(position, buf)
     }
 
     #[inline]
     #[cfg(target_family = "unix")]
-    fn read_at(&mut self, position: u64, mut buf: &mut [u8]) -> io::Result<u64> {
+    fn read_at(&mut self, position: u64, buf: &mut [u8]) -> io::Result<u64> {
         use std::os::unix::fs::FileExt;
 
-        self.file.read_exact_at(&mut buf, position)?;
+        let position = self.header_width + position;
+        match &self.writer {
+            #[cfg(target_os = "linux")]
+            Writer::Direct(direct) => direct.read_exact_at(buf, position)?,
+            _ => self.file.read_exact_at(buf, position)?,
+        }
 
         Ok(buf.len() as u64)
     }
@@ -98,21 +317,183 @@ impl Segment {
     fn read_at(&mut self, position: u64, mut buf: &mut [u8]) -> io::Result<u64> {
         use std::io::{Read, Seek, SeekFrom};
 
-        self.file.seek(SeekFrom::Start(position))?;
+        self.file
+            .seek(SeekFrom::Start(self.header_width + position))?;
         self.file.read_exact(&mut buf)?;
 
         Ok(buf.len() as u64)
     }
 
+    /// Writes buffered records to the file
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Flushes the records and syncs them to the disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.file.sync_data()
+    }
+
+    /// Flushes the records and returns the file descriptor and file position
+    /// of the given position for reads outside the segment. Returns `None`
+    /// for direct I/O segments as they need aligned reads
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    pub fn read_target(&mut self, position: u64) -> io::Result<Option<(RawFd, u64)>> {
+        if self.writer.is_direct() {
+            return Ok(None);
+        }
+
+        self.writer.flush()?;
+        Ok(Some((self.file.as_raw_fd(), self.header_width + position)))
+    }
+
+    /// File descriptor of the segment
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    pub fn fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+
+    /// Writes `len` bytes from the position to the file descriptor with
+    /// `sendfile`. Bytes go from the page cache to the descriptor without
+    /// being copied to user space
+    #[cfg(target_os = "linux")]
+    pub fn send(&mut self, position: u64, len: u64, fd: BorrowedFd<'_>) -> io::Result<()> {
+        self.writer.flush()?;
+
+        let mut offset = (self.header_width + position) as libc::off_t;
+        let mut remaining = len;
+        while remaining > 0 {
+            let count = remaining.min(isize::MAX as u64) as usize;
+            let n = unsafe {
+                libc::sendfile(fd.as_raw_fd(), self.file.as_raw_fd(), &mut offset, count)
+            };
+            match n {
+                -1 => match io::Error::last_os_error() {
+                    e if e.kind() == io::ErrorKind::Interrupted => continue,
+                    e => return Err(e),
+                },
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                n => remaining -= n as u64,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops everything from the given position
+    pub fn truncate(&mut self, position: u64) -> io::Result<()> {
+        self.writer.flush()?;
+        if position >= self.size {
+            return Ok(());
+        }
+
+        let end = self.header_width + position;
+        match &mut self.writer {
+            Writer::ReadOnly => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Segment is read-only",
+                ))
+            }
+            Writer::Buffered(writer) => {
+                self.file.set_len(end)?;
+                writer.get_mut().seek(SeekFrom::Start(end))?;
+            }
+            #[cfg(target_os = "linux")]
+            Writer::Direct(writer) => writer.truncate(end)?,
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            Writer::Ring(writer) => writer.truncate(end)?,
+        }
+
+        self.size = position;
+        Ok(())
+    }
+
+    /// Whether everything from the position to the end of the segment is the
+    /// zeros which direct I/O pads the last block with. Padding is trimmed on
+    /// close and is only left behind when the segment isn't closed
+    #[cfg(target_os = "linux")]
+    pub fn is_padding(&mut self, position: u64) -> io::Result<bool> {
+        let end = self.header_width + self.size;
+        if !end.is_multiple_of(BLOCK as u64) || self.size - position >= BLOCK as u64 {
+            return Ok(false);
+        }
+
+        let mut rest = vec![0; (self.size - position) as usize];
+        self.read(position, &mut rest)?;
+        Ok(rest.iter().all(|byte| *byte == 0))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn is_padding(&mut self, _position: u64) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Drops the padding after the position. Files of read-only segments aren't
+    /// touched and only reads stop at the position
+    pub fn trim_padding(&mut self, position: u64) -> io::Result<()> {
+        if self.read_only {
+            self.size = self.size.min(position);
+            return Ok(());
+        }
+
+        self.truncate(position)
+    }
+
+    /// Flushes the records. Preallocated space and padding of direct I/O after
+    /// the records are given back
     pub fn close(&mut self) -> io::Result<()> {
         self.writer.flush()?;
+        if self.read_only {
+            return Ok(());
+        }
+
+        if self.preallocated || self.writer.is_direct() {
+            self.file.set_len(self.header_width + self.size)?;
+            self.preallocated = false;
+        }
+
         Ok(())
     }
 }
 
+/// Allocates disk space for the first `len` bytes of the file without changing
+/// its length. Segments aren't preallocated when the filesystem doesn't support
+/// `fallocate`
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mode = libc::FALLOC_FL_KEEP_SIZE;
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), mode, 0, len as libc::off_t) };
+    if ret == 0 {
+        return Ok(());
+    }
+
+    match io::Error::last_os_error() {
+        e if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+            warn!("Preallocation unavailable. Error = {:?}", e);
+            Ok(())
+        }
+        e => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate(_file: &File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// Returns length and flags of the record from its header
+pub fn parse_header(header: &[u8]) -> (u64, u8) {
+    let len = BigEndian::read_u32(&header[..4]) as u64;
+    (len, header[4])
+}
+
 #[cfg(test)]
 mod test {
-    use super::Segment;
+    use super::{Segment, SegmentOptions, HEADER_WIDTH};
     use pretty_assertions::assert_eq;
 
     #[test]
@@ -132,7 +513,7 @@ mod test {
 
             let mut next_pos = 0;
             for _i in 0..10 {
-                let mut data = vec![0; len as usize];
+                let mut data = vec![0; len];
                 segment.read(next_pos, &mut data).unwrap();
                 assert_eq!(&data, record);
                 next_pos += len as u64;
@@ -163,6 +544,138 @@ mod test {
         }
     }
 
+    #[test]
+    fn records_with_headers_are_self_delimiting() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut segment = Segment::new(&dir, 0).unwrap();
+
+        let mut position = 0;
+        for i in 0..10u8 {
+            let record = vec![i; 10 + i as usize];
+            let (offset, pos) = segment.append_with_header(&record, i).unwrap();
+            assert_eq!((offset, pos), (i as u64, position));
+            position += HEADER_WIDTH + record.len() as u64;
+        }
+
+        assert_eq!(segment.size(), position);
+
+        // walk through records using only the headers
+        let mut position = 0;
+        for i in 0..10u8 {
+            let (len, flags) = segment.read_header(position).unwrap();
+            assert_eq!((len, flags), (10 + i as u64, i));
+
+            let mut record = vec![0; len as usize];
+            segment.read(position + HEADER_WIDTH, &mut record).unwrap();
+            assert_eq!(record, vec![i; len as usize]);
+            position += HEADER_WIDTH + len;
+        }
+
+        assert!(segment.read_header(position).is_err());
+    }
+
+    #[test]
+    fn segments_are_identified_by_their_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut segment = Segment::open(&dir, 10, false, true, SegmentOptions::default()).unwrap();
+        segment.append_with_header(b"hello", 0).unwrap();
+        segment.close().unwrap();
+
+        let file = dir.path().join(format!("{:020}.segment", 10));
+        assert_eq!(std::fs::metadata(&file).unwrap().len(), 16 + 10);
+
+        let mut segment = Segment::open(&dir, 10, false, false, SegmentOptions::default()).unwrap();
+        assert!(segment.is_framed());
+        assert_eq!(segment.size(), 10);
+        assert_eq!(segment.read_header(0).unwrap(), (5, 0));
+
+        // headers with wrong base offset and segments without headers are rejected
+        std::fs::copy(&file, dir.path().join(format!("{:020}.segment", 11))).unwrap();
+        assert!(Segment::open(&dir, 11, false, true, SegmentOptions::default()).is_err());
+        std::fs::write(dir.path().join(format!("{:020}.segment", 12)), b"hello").unwrap();
+        assert!(Segment::open(&dir, 12, false, false, SegmentOptions::default()).is_err());
+        assert_eq!(
+            Segment::open(&dir, 12, true, false, SegmentOptions::default())
+                .unwrap()
+                .size(),
+            5
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sealed_direct_segments_are_read_around_the_page_cache() {
+        use super::Writer;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = SegmentOptions {
+            direct: true,
+            ..SegmentOptions::default()
+        };
+
+        let mut segment = Segment::open(&dir, 0, false, false, options.clone()).unwrap();
+        segment.append(&[1; 100]).unwrap();
+        segment.close().unwrap();
+
+        // reads go through the aligned reads of direct I/O. no write buffer
+        options.read_only = true;
+        let mut segment = Segment::open(&dir, 0, false, false, options).unwrap();
+        match &segment.writer {
+            Writer::Direct(direct) => assert!(!direct.has_buffer()),
+            _ => panic!("Expecting a direct I/O reader"),
+        }
+
+        let mut record = vec![0; 100];
+        segment.read(0, &mut record).unwrap();
+        assert_eq!(record, vec![1; 100]);
+        assert!(segment.append(&[2; 100]).is_err());
+        assert!(segment.truncate(50).is_err());
+        match &segment.writer {
+            Writer::Direct(direct) => assert!(!direct.has_buffer()),
+            _ => panic!("Expecting a direct I/O reader"),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn preallocated_segments_are_trimmed_on_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join(format!("{:020}.segment", 0));
+        let len = || std::fs::metadata(&file).unwrap().len();
+        let preallocate = SegmentOptions {
+            preallocate: Some(4096),
+            ..SegmentOptions::default()
+        };
+
+        let mut segment = Segment::open(&dir, 0, false, false, preallocate.clone()).unwrap();
+        let metadata = std::fs::metadata(&file).unwrap();
+        assert_eq!(metadata.len(), 16);
+        assert!(std::os::unix::fs::MetadataExt::blocks(&metadata) * 512 >= 4096);
+        for i in 0..10u8 {
+            assert_eq!(
+                segment.append(&[i; 100]).unwrap(),
+                (i as u64, i as u64 * 100)
+            );
+        }
+
+        segment.close().unwrap();
+        assert_eq!(len(), 16 + 1000);
+
+        // appends continue after the records and not after preallocated space
+        let mut segment = Segment::open(&dir, 0, false, false, preallocate).unwrap();
+        assert_eq!(segment.size(), 1000);
+        segment.set_next_offset(10);
+        assert_eq!(segment.append(&[10; 100]).unwrap(), (10, 1000));
+        segment.truncate(500).unwrap();
+        assert_eq!(segment.append(&[11; 100]).unwrap(), (11, 500));
+        segment.close().unwrap();
+        assert_eq!(len(), 16 + 600);
+
+        let mut record = vec![0; 100];
+        segment.read(500, &mut record).unwrap();
+        assert_eq!(record, vec![11; 100]);
+    }
+
     /*
     #[test]
     fn vectored_reads_works_as_expected() {
@@ -0,0 +1,415 @@
+use io_uring::{opcode, squeue, types, IoUring};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+/// Size of the submission queue. Bigger batches are submitted in rounds
+const ENTRIES: u32 = 64;
+
+/// `IORING_ENTER_GETEVENTS`. Waits for completions without submitting entries
+const GETEVENTS: u32 = 1;
+
+/// Size of the write buffer of segments
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Read of a complete buffer at a position of a file
+pub(crate) struct Read<'a> {
+    pub fd: RawFd,
+    pub position: u64,
+    pub buf: &'a mut [u8],
+}
+
+/// io_uring instance shared by all the segments of a log. Reads, writes and
+/// fsyncs are submitted in batches and waited on together. A ring which can't
+/// be replaced after a failed submission is dropped and blocking I/O is used
+/// from then on
+#[derive(Clone)]
+pub(crate) struct Ring {
+    ring: Arc<Mutex<Option<IoUring>>>,
+}
+
+impl fmt::Debug for Ring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ring").finish()
+    }
+}
+
+impl Ring {
+    /// Creates a ring. Returns `None` when the kernel doesn't support io_uring
+    /// or doesn't allow it, in which case blocking I/O is used
+    pub fn new() -> Option<Ring> {
+        match IoUring::new(ENTRIES) {
+            Ok(ring) => Some(Ring {
+                ring: Arc::new(Mutex::new(Some(ring))),
+            }),
+            Err(e) => {
+                warn!("io_uring unavailable. Error = {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Fills all the buffers. Short reads are resubmitted for the rest of
+    /// their buffer
+    pub fn read_all(&self, reads: &mut [Read]) -> io::Result<()> {
+        let mut filled = vec![0; reads.len()];
+        let mut pending: Vec<usize> = (0..reads.len()).collect();
+        while !pending.is_empty() {
+            let entries = pending
+                .iter()
+                .map(|&i| {
+                    let read = &mut reads[i];
+                    let buf = &mut read.buf[filled[i]..];
+                    let len = buf.len().min(u32::MAX as usize) as u32;
+                    opcode::Read::new(types::Fd(read.fd), buf.as_mut_ptr(), len)
+                        .offset(read.position + filled[i] as u64)
+                        .build()
+                })
+                .collect();
+
+            // buffers outlive the submission as it waits for all the entries
+            let results = match unsafe { self.submit(entries)? } {
+                Some(results) => results,
+                None => {
+                    for i in pending {
+                        let read = &mut reads[i];
+                        let position = read.position + filled[i] as u64;
+                        borrow(read.fd).read_exact_at(&mut read.buf[filled[i]..], position)?;
+                    }
+
+                    return Ok(());
+                }
+            };
+
+            let mut next = Vec::new();
+            for (i, result) in pending.into_iter().zip(results) {
+                match result? {
+                    0 if !reads[i].buf.is_empty() => {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+                    }
+                    n => filled[i] += n,
+                }
+
+                if filled[i] < reads[i].buf.len() {
+                    next.push(i);
+                }
+            }
+
+            pending = next;
+        }
+
+        Ok(())
+    }
+
+    /// Writes all the data at the position of the file
+    pub fn write_all_at(&self, fd: RawFd, mut position: u64, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let len = data.len().min(u32::MAX as usize) as u32;
+            let entry = opcode::Write::new(types::Fd(fd), data.as_ptr(), len)
+                .offset(position)
+                .build();
+
+            let results = match unsafe { self.submit(vec![entry])? } {
+                Some(results) => results,
+                None => return borrow(fd).write_all_at(data, position),
+            };
+
+            match results.into_iter().next().unwrap()? {
+                0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                n => {
+                    data = &data[n..];
+                    position += n as u64;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Syncs data of all the files in one batch
+    pub fn sync_all(&self, fds: &[RawFd]) -> io::Result<()> {
+        let entries = fds
+            .iter()
+            .map(|&fd| {
+                opcode::Fsync::new(types::Fd(fd))
+                    .flags(types::FsyncFlags::DATASYNC)
+                    .build()
+            })
+            .collect();
+
+        let results = match unsafe { self.submit(entries)? } {
+            Some(results) => results,
+            None => {
+                for &fd in fds {
+                    borrow(fd).sync_data()?;
+                }
+
+                return Ok(());
+            }
+        };
+
+        for result in results {
+            result?;
+        }
+
+        Ok(())
+    }
+
+    /// Submits the entries and waits for all of them to complete. Returns
+    /// result of every entry in order or `None` when the ring is dropped and
+    /// the caller should fall back to blocking I/O. Entries which are submitted
+    /// before an error are still waited on and entries which aren't are
+    /// dropped with the ring
+    ///
+    /// # Safety
+    ///
+    /// Buffers of the entries should be valid till this returns
+    unsafe fn submit(
+        &self,
+        entries: Vec<squeue::Entry>,
+    ) -> io::Result<Option<Vec<io::Result<usize>>>> {
+        let mut guard = self.ring.lock().unwrap();
+        let ring = match guard.as_mut() {
+            Some(ring) => ring,
+            None => return Ok(None),
+        };
+
+        let mut results: Vec<io::Result<usize>> = Vec::with_capacity(entries.len());
+        results.resize_with(entries.len(), || Ok(0));
+
+        for (batch, entries) in entries.chunks(ENTRIES as usize).enumerate() {
+            let first = batch * ENTRIES as usize;
+            {
+                let mut sq = ring.submission();
+                for (i, entry) in entries.iter().enumerate() {
+                    let entry = entry.clone().user_data((first + i) as u64);
+                    // queue has room for a complete batch as every batch is waited on
+                    sq.push(&entry).unwrap();
+                }
+            }
+
+            // entries should complete before their buffers are dropped. keep waiting
+            // for the submitted entries even after an error
+            let (mut submitted, mut done) = (0, 0);
+            let mut error = None;
+            while done < entries.len() {
+                let waited = match error {
+                    None => ring.submit_and_wait(entries.len() - done),
+                    Some(_) if done == submitted => break,
+                    Some(_) => ring
+                        .submitter()
+                        .enter::<libc::sigset_t>(0, 1, GETEVENTS, None),
+                };
+
+                match waited {
+                    Ok(n) if error.is_none() => submitted += n,
+                    Ok(_) => (),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) if error.is_none() => error = Some(e),
+                    Err(e) => warn!("Failed to wait for io_uring entries. Error = {:?}", e),
+                }
+
+                for cqe in ring.completion() {
+                    let result = match cqe.result() {
+                        n if n < 0 => Err(io::Error::from_raw_os_error(-n)),
+                        n => Ok(n as usize),
+                    };
+
+                    results[cqe.user_data() as usize] = result;
+                    done += 1;
+                }
+            }
+
+            if let Some(e) = error {
+                // entries which are left in the submission queue point to buffers
+                // which are about to be dropped. a new ring never submits them.
+                // without a new ring the old one is dropped along with them
+                if submitted < entries.len() {
+                    match IoUring::new(ENTRIES) {
+                        Ok(new) => *ring = new,
+                        Err(e) => {
+                            warn!("Failed to replace io_uring. Error = {:?}", e);
+                            *guard = None;
+                        }
+                    }
+                }
+
+                return Err(e);
+            }
+        }
+
+        Ok(Some(results))
+    }
+}
+
+/// File of the descriptor for blocking I/O. Descriptor isn't closed when the
+/// file is dropped
+fn borrow(fd: RawFd) -> ManuallyDrop<File> {
+    // descriptors of reads, writes and syncs are open till they return
+    ManuallyDrop::new(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Writes of a segment which are buffered and written at explicit positions
+/// through the ring. File shouldn't be opened in append mode as positions of
+/// writes would be ignored.
+///
+/// Every flush writes the buffer in one entry and waits for it. Writes of a
+/// segment aren't queued behind each other as the ring is shared by all the
+/// segments and a submission waits for all its entries, and as reads of
+/// records expect them to be in the file once they are flushed. Appends are
+/// already batched by the 1MB buffer. The ring mostly batches reads of sweeps
+/// and fsyncs across segments
+pub(crate) struct RingWriter {
+    ring: Ring,
+    file: File,
+    buf: Vec<u8>,
+    /// File position of the start of the buffer
+    position: u64,
+}
+
+impl RingWriter {
+    pub fn new(ring: Ring, file: File, position: u64) -> RingWriter {
+        RingWriter {
+            ring,
+            file,
+            buf: Vec::new(),
+            position,
+        }
+    }
+
+    pub fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= BUFFER_SIZE {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let fd = self.file.as_raw_fd();
+        self.ring.write_all_at(fd, self.position, &self.buf)?;
+        self.position += self.buf.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Drops everything after `end`. Writes continue from there
+    pub fn truncate(&mut self, end: u64) -> io::Result<()> {
+        self.flush()?;
+        self.file.set_len(end)?;
+        self.position = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Read, Ring, RingWriter};
+    use pretty_assertions::assert_eq;
+    use std::fs::OpenOptions;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn batched_reads_and_writes_work() {
+        let ring = match Ring::new() {
+            Some(ring) => ring,
+            None => return,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+
+        let mut writer = RingWriter::new(ring.clone(), file.try_clone().unwrap(), 0);
+        let mut expected = Vec::new();
+        for i in 0..200u8 {
+            let record = vec![i; 10_000];
+            writer.write_all(&record).unwrap();
+            expected.extend_from_slice(&record);
+        }
+
+        writer.flush().unwrap();
+        ring.sync_all(&[file.as_raw_fd()]).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap().len(), expected.len());
+
+        // more reads than the size of the queue
+        let mut bufs = vec![vec![0; 1000]; 150];
+        let mut reads: Vec<Read> = bufs
+            .iter_mut()
+            .enumerate()
+            .map(|(i, buf)| Read {
+                fd: file.as_raw_fd(),
+                position: i as u64 * 13_000,
+                buf,
+            })
+            .collect();
+
+        ring.read_all(&mut reads).unwrap();
+        for (i, buf) in bufs.iter().enumerate() {
+            let position = i * 13_000;
+            assert_eq!(buf, &expected[position..position + 1000]);
+        }
+
+        // reads past the end of the file
+        let mut buf = vec![0; 10];
+        let mut reads = vec![Read {
+            fd: file.as_raw_fd(),
+            position: expected.len() as u64 - 5,
+            buf: &mut buf,
+        }];
+        assert!(ring.read_all(&mut reads).is_err());
+    }
+
+    #[test]
+    fn dropped_rings_fall_back_to_blocking_io() {
+        let ring = match Ring::new() {
+            Some(ring) => ring,
+            None => return,
+        };
+
+        // ring which couldn't be replaced after a failed submission
+        ring.ring.lock().unwrap().take();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+
+        let mut writer = RingWriter::new(ring.clone(), file.try_clone().unwrap(), 0);
+        writer.write_all(b"hello world").unwrap();
+        writer.flush().unwrap();
+        writer.truncate(5).unwrap();
+        writer.write_all(b" ring").unwrap();
+        writer.flush().unwrap();
+        ring.sync_all(&[file.as_raw_fd()]).unwrap();
+
+        let mut buf = vec![0; 10];
+        let mut reads = vec![Read {
+            fd: file.as_raw_fd(),
+            position: 0,
+            buf: &mut buf,
+        }];
+        ring.read_all(&mut reads).unwrap();
+        assert_eq!(buf, b"hello ring");
+    }
+}
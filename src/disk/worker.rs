@@ -0,0 +1,278 @@
+use super::archive::ArchiveStore;
+use super::chunk::Chunk;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Extension added to files of removed chunks till they are deleted
+pub(crate) const DELETED: &str = "deleted";
+
+/// Opens the next active chunk
+pub(crate) type Build = Box<dyn FnOnce() -> io::Result<Chunk> + Send>;
+
+enum Task {
+    /// Closes the chunk if it's open and deletes its (renamed) files
+    Delete(Option<Chunk>, Vec<PathBuf>),
+    /// Builds the next active chunk and sends it back on the sender
+    Stage(Build, Sender<io::Result<Chunk>>),
+    /// Closes a sealed chunk. Reports the result on the sender
+    Close(Chunk, Sender<io::Result<()>>),
+    /// Closes the chunk if it's open and moves its files to the archive.
+    /// Reports the result on the sender
+    Archive(
+        Arc<dyn ArchiveStore>,
+        u64,
+        Option<Chunk>,
+        Sender<io::Result<()>>,
+    ),
+    /// Reports that all the previous tasks are done
+    Sync(Sender<()>),
+}
+
+/// Background thread which takes file operations off the append path. Next
+/// active chunk is built once the active chunk is full, sealed chunks are
+/// closed and removed chunks are closed and deleted or archived in the
+/// background
+pub(crate) struct Worker {
+    dir: PathBuf,
+    tx: Option<Sender<Task>>,
+    handle: Option<JoinHandle<()>>,
+    /// Base offset of the chunk which is being built and where it's sent
+    staged: Option<(u64, Receiver<io::Result<Chunk>>)>,
+    /// Base offsets of sealed chunks which are being closed or archived along
+    /// with where the result is sent
+    pending: Vec<(u64, Receiver<io::Result<()>>)>,
+}
+
+impl Worker {
+    pub fn new(dir: &Path) -> io::Result<Worker> {
+        let (tx, rx) = mpsc::channel();
+        let thread_dir = dir.to_owned();
+        let handle = thread::Builder::new()
+            .name("segments-worker".to_owned())
+            .spawn(move || run(thread_dir, rx))?;
+
+        let worker = Worker {
+            dir: dir.to_owned(),
+            tx: Some(tx),
+            handle: Some(handle),
+            staged: None,
+            pending: Vec::new(),
+        };
+
+        Ok(worker)
+    }
+
+    /// Starts building the active chunk with the given base offset. A chunk
+    /// which is staged for another base offset is discarded
+    pub fn stage(&mut self, base_offset: u64, build: Build) -> io::Result<()> {
+        match self.staged.as_ref() {
+            Some((staged, _)) if *staged == base_offset => return Ok(()),
+            Some(_) => self.discard_staged()?,
+            None => (),
+        }
+
+        let (tx, rx) = mpsc::channel();
+        self.send(Task::Stage(build, tx));
+        self.staged = Some((base_offset, rx));
+        Ok(())
+    }
+
+    /// Returns the chunk staged for the given base offset. Waits for it when
+    /// it's still being built. Returns `None` when nothing is staged for the
+    /// base offset, in which case the log opens the chunk itself
+    pub fn take_staged(&mut self, base_offset: u64) -> io::Result<Option<Chunk>> {
+        match self.staged.as_ref() {
+            Some((staged, _)) if *staged == base_offset => (),
+            Some(_) => {
+                self.discard_staged()?;
+                return Ok(None);
+            }
+            None => return Ok(None),
+        }
+
+        let (_, rx) = self.staged.take().unwrap();
+        match rx.recv() {
+            Ok(Ok(chunk)) => Ok(Some(chunk)),
+            Ok(Err(e)) => {
+                warn!("Failed to stage chunk {}. Error = {:?}", base_offset, e);
+                Ok(None)
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Deletes the staged chunk, e.g when the log is truncated before it's
+    /// used or when the log is closed
+    pub fn discard_staged(&mut self) -> io::Result<()> {
+        let (base_offset, rx) = match self.staged.take() {
+            Some(staged) => staged,
+            None => return Ok(()),
+        };
+
+        match rx.recv() {
+            Ok(Ok(mut chunk)) => {
+                chunk.close()?;
+                self.delete(base_offset, Some(chunk))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Closes the sealed chunk. Files of the chunk shouldn't be touched till
+    /// `wait` returns. Errors are returned by `wait`, `reap` or `sync`
+    pub fn close(&mut self, base_offset: u64, chunk: Chunk) {
+        let (tx, rx) = mpsc::channel();
+        self.send(Task::Close(chunk, tx));
+        self.pending.push((base_offset, rx));
+    }
+
+    /// Moves files of the chunk to the archive. Files of the chunk shouldn't be
+    /// read till `wait` returns, which also returns errors of the move
+    pub fn archive(
+        &mut self,
+        store: Arc<dyn ArchiveStore>,
+        base_offset: u64,
+        chunk: Option<Chunk>,
+    ) {
+        let (tx, rx) = mpsc::channel();
+        self.send(Task::Archive(store, base_offset, chunk, tx));
+        self.pending.push((base_offset, rx));
+    }
+
+    /// Waits till the chunk with the given base offset is closed and archived
+    /// if it's being closed or archived. Returns the first error
+    pub fn wait(&mut self, base_offset: u64) -> io::Result<()> {
+        let mut result = Ok(());
+        while let Some(i) = self
+            .pending
+            .iter()
+            .position(|(offset, _)| *offset == base_offset)
+        {
+            let (_, rx) = self.pending.remove(i);
+            let done = rx.recv().unwrap_or_else(|_| Err(stopped()));
+            result = result.and(done);
+        }
+
+        result
+    }
+
+    /// Forgets chunks which are done closing or archiving. Returns the first
+    /// error among them
+    pub fn reap(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        let mut i = 0;
+        while i < self.pending.len() {
+            let done = match self.pending[i].1.try_recv() {
+                Ok(done) => done,
+                Err(TryRecvError::Empty) => {
+                    i += 1;
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => Err(stopped()),
+            };
+
+            self.pending.remove(i);
+            result = result.and(done);
+        }
+
+        result
+    }
+
+    /// Deletes files of the chunk. Files are renamed right away so that a new
+    /// chunk with the same base offset isn't affected by the deletion. Chunks
+    /// which aren't open are only deleted
+    pub fn delete(&self, base_offset: u64, chunk: Option<Chunk>) -> io::Result<()> {
+        let mut files = Vec::new();
+        for extension in ["index", "segment"] {
+            let file = self.dir.join(format!("{:020}.{}", base_offset, extension));
+            let deleted = file.with_extension(format!("{}.{}", extension, DELETED));
+            fs::rename(file, &deleted)?;
+            files.push(deleted);
+        }
+
+        self.send(Task::Delete(chunk, files));
+        Ok(())
+    }
+
+    /// Waits for all the pending tasks. Returns the first error of chunks which
+    /// are closed or archived
+    pub fn sync(&mut self) -> io::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Task::Sync(tx));
+        let _ = rx.recv();
+
+        let mut result = Ok(());
+        for (_, rx) in self.pending.drain(..) {
+            let done = rx.recv().unwrap_or_else(|_| Err(stopped()));
+            result = result.and(done);
+        }
+
+        result
+    }
+
+    fn send(&self, task: Task) {
+        if let Some(tx) = &self.tx {
+            if tx.send(task).is_err() {
+                warn!("Background worker of {:?} stopped", self.dir);
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if let Err(e) = self.discard_staged() {
+            warn!(
+                "Failed to discard staged chunk in {:?}. Error = {:?}",
+                self.dir, e
+            );
+        }
+
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("Background worker stopped")
+}
+
+fn run(dir: PathBuf, rx: Receiver<Task>) {
+    for task in rx {
+        match task {
+            Task::Delete(chunk, files) => {
+                drop(chunk);
+                for file in files {
+                    match fs::remove_file(&file) {
+                        Ok(()) => (),
+                        // a chunk with the same base offset was removed again before this task
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                        Err(e) => warn!("Failed to delete {:?}. Error = {:?}", file, e),
+                    }
+                }
+            }
+            Task::Stage(build, done) => {
+                let _ = done.send(build());
+            }
+            Task::Close(mut chunk, done) => {
+                let closed = chunk.close();
+                drop(chunk);
+                let _ = done.send(closed);
+            }
+            Task::Archive(store, base_offset, chunk, done) => {
+                drop(chunk);
+                let _ = done.send(store.archive(&dir, base_offset));
+            }
+            Task::Sync(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
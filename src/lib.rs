@@ -4,5 +4,8 @@ extern crate log;
 mod disk;
 mod memory;
 
-pub use disk::DiskLog;
+pub use disk::{
+    ArchiveStore, Compression, Config, DirArchive, DiskLog, Encryption, IndexEncoding,
+    IndexInterval, LogManager, PartitionedLog, RecordTooLarge,
+};
 pub use memory::MemoryLog;
@@ -201,7 +201,7 @@ impl<T: Debug + Clone> MemoryLog<T> {
         }
 
         progress.1 += count as u64;
-        return Some(progress);
+        Some(progress)
     }
 }
 
@@ -51,10 +51,7 @@ impl<T: Debug + Clone> Segment<T> {
         }
 
         let offset = offset - self.base_offset;
-        match self.file.get(offset as usize) {
-            Some(record) => Some(record.clone()),
-            None => None,
-        }
+        self.file.get(offset as usize).cloned()
     }
 
     /// Reads multiple data from an offset to the end of segment